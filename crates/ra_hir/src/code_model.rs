@@ -79,6 +79,12 @@ impl Crate {
         Some(Module::new(self, module_id))
     }
 
+    /// The module whose items are in scope everywhere in this crate, usually
+    /// `std::prelude::v1`.
+    pub fn prelude(self, db: &impl DefDatabase) -> Option<Module> {
+        db.crate_def_map(self.id).prelude().map(Module::from)
+    }
+
     pub fn root_file(self, db: &impl DefDatabase) -> FileId {
         db.crate_graph().crate_root(self.id)
     }
@@ -10,8 +10,8 @@ pub use hir_def::db::{
     TraitDataQuery, TypeAliasDataQuery, UnionDataQuery,
 };
 pub use hir_expand::db::{
-    AstDatabase, AstDatabaseStorage, AstIdMapQuery, InternMacroQuery, MacroArgQuery, MacroDefQuery,
-    MacroExpandQuery, ParseMacroQuery,
+    AstDatabase, AstDatabaseGroupKey__, AstDatabaseStorage, AstIdMapQuery, InternMacroQuery,
+    MacroArgQuery, MacroDefQuery, MacroExpandQuery, ParseMacroQuery,
 };
 pub use hir_ty::db::{
     AssociatedTyDataQuery, AssociatedTyValueQuery, CallableItemSignatureQuery, DoInferQuery,
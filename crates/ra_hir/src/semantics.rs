@@ -49,12 +49,62 @@ impl<'db, DB: HirDatabase> Semantics<'db, DB> {
     }
 
     pub fn expand(&self, macro_call: &ast::MacroCall) -> Option<SyntaxNode> {
+        self.expand_and_lower(macro_call).map(|(node, _)| node)
+    }
+
+    /// Expands `macro_call` and parses the expansion, returning the tree
+    /// together with the macro file it belongs to.
+    pub fn expand_and_lower(&self, macro_call: &ast::MacroCall) -> Option<(SyntaxNode, HirFileId)> {
         let macro_call = self.find_file(macro_call.syntax().clone()).with_value(macro_call);
         let sa = self.analyze2(macro_call.map(|it| it.syntax()), None);
         let file_id = sa.expand(self.db, macro_call)?;
         let node = self.db.parse_or_expand(file_id)?;
         self.cache(node.clone(), file_id);
-        Some(node)
+        Some((node, file_id))
+    }
+
+    /// Returns the range of the `macro_rules!` rule used to expand `macro_call`.
+    pub fn matched_macro_rule(&self, macro_call: &ast::MacroCall) -> Option<FileRange> {
+        let macro_call = self.find_file(macro_call.syntax().clone()).with_value(macro_call);
+        let sa = self.analyze2(macro_call.map(|it| it.syntax()), None);
+        let file_id = sa.expand(self.db, macro_call)?;
+        let rule = file_id.expansion_info(self.db)?.matched_rule()?;
+        let res = match rule.file_id.call_node(self.db) {
+            None => FileRange { file_id: rule.file_id.original_file(self.db), range: rule.value },
+            // The macro is itself defined by a macro expansion
+            Some(call) => original_range(self.db, call.as_ref()),
+        };
+        Some(res)
+    }
+
+    /// Returns the index of the `macro_rules!` rule used to expand `macro_call`.
+    pub fn matched_macro_rule_index(&self, macro_call: &ast::MacroCall) -> Option<usize> {
+        let macro_call = self.find_file(macro_call.syntax().clone()).with_value(macro_call);
+        let sa = self.analyze2(macro_call.map(|it| it.syntax()), None);
+        let file_id = sa.expand(self.db, macro_call)?;
+        file_id.expansion_info(self.db)?.matched_rule_index()
+    }
+
+    /// Returns the metavariables bound outside of repetitions when expanding
+    /// `macro_call`, with the ranges of the arguments they are bound to.
+    pub fn macro_bindings(&self, macro_call: &ast::MacroCall) -> Vec<(String, FileRange)> {
+        let macro_call = self.find_file(macro_call.syntax().clone()).with_value(macro_call);
+        let sa = self.analyze2(macro_call.map(|it| it.syntax()), None);
+        let expansion =
+            match sa.expand(self.db, macro_call).and_then(|it| it.expansion_info(self.db)) {
+                Some(it) => it,
+                None => return Vec::new(),
+            };
+        expansion
+            .simple_bindings()
+            .into_iter()
+            // Arguments written by another macro aren't mapped back to the source
+            .filter(|(_, range)| range.file_id.call_node(self.db).is_none())
+            .map(|(name, range)| {
+                let file_id = range.file_id.original_file(self.db);
+                (name.to_string(), FileRange { file_id, range: range.value })
+            })
+            .collect()
     }
 
     pub fn descend_into_macros(&self, token: SyntaxToken) -> SyntaxToken {
@@ -81,6 +131,29 @@ impl<'db, DB: HirDatabase> Semantics<'db, DB> {
         token.value
     }
 
+    /// Maps a token of a macro expansion to the token it was produced from,
+    /// either in the macro definition or in the arguments of the call.
+    pub fn original_token(&self, token: &SyntaxToken) -> Option<SyntaxToken> {
+        let file = self.find_file(token.parent());
+        let expansion = file.file_id.expansion_info(self.db)?;
+        let (token, _origin) = expansion.map_token_up(file.with_value(token))?;
+        Some(token.value)
+    }
+
+    /// Like `original_token`, but follows the token up through all levels of
+    /// expansion, to the range in the file it was written in.
+    pub fn original_token_range(&self, token: &SyntaxToken) -> Option<FileRange> {
+        let mut token = self.find_file(token.parent()).with_value(token.clone());
+        while let Some(expansion) = token.file_id.expansion_info(self.db) {
+            let (up, _origin) = expansion.map_token_up(token.as_ref())?;
+            token = up;
+        }
+        Some(FileRange {
+            file_id: token.file_id.original_file(self.db),
+            range: token.value.text_range(),
+        })
+    }
+
     pub fn original_range(&self, node: &SyntaxNode) -> FileRange {
         let node = self.find_file(node.clone());
         original_range(self.db, node.as_ref())
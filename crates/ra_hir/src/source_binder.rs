@@ -8,7 +8,9 @@ use hir_def::{
     ConstId, DefWithBodyId, EnumId, EnumVariantId, FunctionId, GenericDefId, ImplId, ModuleId,
     StaticId, StructFieldId, StructId, TraitId, TypeAliasId, TypeParamId, UnionId, VariantId,
 };
-use hir_expand::{name::AsName, AstId, InFile, MacroDefId, MacroDefKind};
+use hir_expand::{
+    hygiene::is_local_inner_macros, name::AsName, AstId, InFile, MacroDefId, MacroDefKind,
+};
 use ra_db::FileId;
 use ra_prof::profile;
 use ra_syntax::{
@@ -208,8 +210,9 @@ impl ToId for ast::MacroCall {
         let krate = sb.to_module_def(db, src.file_id.original_file(db))?.id.krate;
 
         let ast_id = Some(AstId::new(src.file_id, db.ast_id_map(src.file_id).ast_id(&src.value)));
+        let local_inner = is_local_inner_macros(&src.value);
 
-        Some(MacroDefId { krate: Some(krate), ast_id, kind })
+        Some(MacroDefId { krate: Some(krate), ast_id, kind, local_inner })
     }
 }
 
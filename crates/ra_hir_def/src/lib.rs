@@ -434,7 +434,7 @@ impl AsMacroCall for InFile<&ast::MacroCall> {
     ) -> Option<MacroCallId> {
         let ast_id = AstId::new(self.file_id, db.ast_id_map(self.file_id).ast_id(self.value));
         let h = Hygiene::new(db, self.file_id);
-        let path = path::ModPath::from_src(self.value.path()?, &h)?;
+        let path = path::ModPath::from_macro_call(self.value.path()?, &h)?;
 
         AstIdWithPath::new(ast_id.file_id, ast_id.value, path).as_call_id(db, resolver)
     }
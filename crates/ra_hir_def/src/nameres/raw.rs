@@ -10,7 +10,7 @@ use std::{ops::Index, sync::Arc};
 use hir_expand::{
     ast_id_map::AstIdMap,
     db::AstDatabase,
-    hygiene::Hygiene,
+    hygiene::{is_local_inner_macros, Hygiene},
     name::{AsName, Name},
 };
 use ra_arena::{impl_arena_id, Arena, RawId};
@@ -204,6 +204,7 @@ pub(super) struct MacroData {
     pub(super) path: ModPath,
     pub(super) name: Option<Name>,
     pub(super) export: bool,
+    pub(super) local_inner: bool,
     pub(super) builtin: bool,
 }
 
@@ -377,21 +378,34 @@ impl RawItemsCollector {
 
     fn add_macro(&mut self, current_module: Option<Module>, m: ast::MacroCall) {
         let attrs = self.parse_attrs(&m);
-        let path = match m.path().and_then(|path| ModPath::from_src(path, &self.hygiene)) {
+        let name = m.name().map(|it| it.as_name());
+        // `macro_rules!` definitions keep their path as is
+        let path = m.path().and_then(|path| match name {
+            Some(_) => ModPath::from_src(path, &self.hygiene),
+            None => ModPath::from_macro_call(path, &self.hygiene),
+        });
+        let path = match path {
             Some(it) => it,
             _ => return,
         };
 
-        let name = m.name().map(|it| it.as_name());
         let ast_id = self.source_ast_id_map.ast_id(&m);
         // FIXME: cfg_attr
         let export = m.attrs().filter_map(|x| x.simple_name()).any(|name| name == "macro_export");
+        let local_inner = is_local_inner_macros(&m);
 
         // FIXME: cfg_attr
         let builtin =
             m.attrs().filter_map(|x| x.simple_name()).any(|name| name == "rustc_builtin_macro");
 
-        let m = self.raw_items.macros.alloc(MacroData { ast_id, path, name, export, builtin });
+        let m = self.raw_items.macros.alloc(MacroData {
+            ast_id,
+            path,
+            name,
+            export,
+            local_inner,
+            builtin,
+        });
         self.push_item(current_module, attrs, RawItemKind::Macro(m));
     }
 
@@ -135,6 +135,62 @@ fn macro_rules_export_with_local_inner_macros_are_visible() {
     "###);
 }
 
+#[test]
+fn macro_rules_local_inner_macros_resolve_in_defining_crate() {
+    let map = def_map(
+        "
+        //- /main.rs crate:main deps:foo
+        macro_rules! inner {
+            ($i:ident) => { struct Wrong; }
+        }
+        foo::structs!(Foo);
+
+        //- /lib.rs crate:foo
+        #[macro_export(local_inner_macros)]
+        macro_rules! structs {
+            ($i:ident) => { inner!($i); }
+        }
+
+        #[macro_export]
+        macro_rules! inner {
+            ($i:ident) => { struct $i; }
+        }
+        ",
+    );
+    assert_snapshot!(map, @r###"
+   ⋮crate
+   ⋮Foo: t v
+    "###);
+}
+
+#[test]
+fn macro_rules_local_inner_macros_keep_calls_passed_as_arguments() {
+    let map = def_map(
+        "
+        //- /main.rs crate:main deps:foo
+        macro_rules! inner {
+            ($i:ident) => { struct $i; }
+        }
+        foo::wrap!(inner!(Foo));
+
+        //- /lib.rs crate:foo
+        #[macro_export(local_inner_macros)]
+        macro_rules! wrap {
+            ($($t:tt)*) => { $($t)* }
+        }
+
+        #[macro_export]
+        macro_rules! inner {
+            ($i:ident) => { struct Wrong; }
+        }
+        ",
+    );
+    assert_snapshot!(map, @r###"
+   ⋮crate
+   ⋮Foo: t v
+    "###);
+}
+
 #[test]
 fn unexpanded_macro_should_expand_by_fixedpoint_loop() {
     let map = def_map(
@@ -624,3 +680,24 @@ fn expand_multiple_derive() {
     );
     assert_eq!(map.modules[map.root].scope.impls().len(), 2);
 }
+
+#[test]
+fn expand_derive_in_listed_order() {
+    let db = TestDB::with_files(
+        "
+        //- /main.rs
+        #[derive(Debug, Clone, PartialEq)]
+        struct Foo;
+        ",
+    );
+    let map = db.crate_def_map(db.test_crate());
+    let traits: Vec<String> = map.modules[map.root]
+        .scope
+        .impls()
+        .filter_map(|it| match db.impl_data(it).target_trait.as_ref()? {
+            crate::type_ref::TypeRef::Path(path) => Some(path.mod_path().to_string()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(traits, ["std::fmt::Debug", "std::clone::Clone", "std::cmp::PartialEq"]);
+}
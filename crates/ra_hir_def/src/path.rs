@@ -12,7 +12,7 @@ use hir_expand::{
     name::{AsName, Name},
 };
 use ra_db::CrateId;
-use ra_syntax::ast;
+use ra_syntax::{ast, AstNode};
 
 use crate::{type_ref::TypeRef, InFile};
 
@@ -47,6 +47,22 @@ impl ModPath {
         lower::lower_path(path, hygiene).map(|it| it.mod_path)
     }
 
+    /// Lowers the path of a macro call. In the expansion of a
+    /// `#[macro_export(local_inner_macros)]` macro, single-segment calls
+    /// refer to macros of the defining crate.
+    pub fn from_macro_call(path: ast::Path, hygiene: &Hygiene) -> Option<ModPath> {
+        let name_ref = path.segment().and_then(|it| it.name_ref());
+        let mut path = ModPath::from_src(path, hygiene)?;
+        if path.kind == PathKind::Plain && path.segments.len() == 1 {
+            let krate =
+                name_ref.and_then(|it| hygiene.local_inner_macros(&it.syntax().first_token()?));
+            if let Some(krate) = krate {
+                path.kind = PathKind::DollarCrate(krate);
+            }
+        }
+        Some(path)
+    }
+
     pub fn from_segments(kind: PathKind, segments: impl IntoIterator<Item = Name>) -> ModPath {
         let segments = segments.into_iter().collect::<Vec<_>>();
         ModPath { kind, segments }
@@ -38,7 +38,12 @@ macro_rules! register_builtin {
                  _ => return None,
             };
 
-            Some(MacroDefId { krate: None, ast_id: None, kind: MacroDefKind::BuiltInDerive(kind) })
+            Some(MacroDefId {
+                krate: None,
+                ast_id: None,
+                kind: MacroDefKind::BuiltInDerive(kind),
+                local_inner: false,
+            })
         }
     };
 }
@@ -148,6 +153,7 @@ fn expand_simple_derive(
     let type_args = make_type_args(info.type_params, Vec::new());
     let trait_path = trait_path.token_trees;
     let expanded = quote! {
+        #[automatically_derived]
         impl ##type_params ##trait_path for #name ##type_args {}
     };
     Ok(expanded)
@@ -240,8 +246,12 @@ mod tests {
         let ast_id_map = db.ast_id_map(file_id.into());
 
         // the first one should be a macro_rules
-        let def =
-            MacroDefId { krate: None, ast_id: None, kind: MacroDefKind::BuiltInDerive(expander) };
+        let def = MacroDefId {
+            krate: None,
+            ast_id: None,
+            kind: MacroDefKind::BuiltInDerive(expander),
+            local_inner: false,
+        };
 
         let loc = MacroCallLoc {
             def,
@@ -266,7 +276,7 @@ mod tests {
             BuiltinDeriveExpander::Copy,
         );
 
-        assert_eq!(expanded, "impl <>std::marker::CopyforFoo <>{}");
+        assert_eq!(expanded, "#[automatically_derived]impl <>std::marker::CopyforFoo <>{}");
     }
 
     #[test]
@@ -281,7 +291,7 @@ mod tests {
 
         assert_eq!(
             expanded,
-            "impl<T0:std::marker::Copy,T1:std::marker::Copy>std::marker::CopyforFoo<T0,T1>{}"
+            "#[automatically_derived]impl<T0:std::marker::Copy,T1:std::marker::Copy>std::marker::CopyforFoo<T0,T1>{}"
         );
     }
 
@@ -299,7 +309,7 @@ mod tests {
 
         assert_eq!(
             expanded,
-            "impl<T0:std::marker::Copy,T1:std::marker::Copy>std::marker::CopyforFoo<T0,T1>{}"
+            "#[automatically_derived]impl<T0:std::marker::Copy,T1:std::marker::Copy>std::marker::CopyforFoo<T0,T1>{}"
         );
     }
 
@@ -315,7 +325,42 @@ mod tests {
 
         assert_eq!(
             expanded,
-            "impl<T0:std::clone::Clone,T1:std::clone::Clone>std::clone::CloneforFoo<T0,T1>{}"
+            "#[automatically_derived]impl<T0:std::clone::Clone,T1:std::clone::Clone>std::clone::CloneforFoo<T0,T1>{}"
         );
     }
+
+    #[test]
+    fn test_clone_expand_with_field_attrs() {
+        let expanded = expand_builtin_derive(
+            r#"
+        #[derive(Clone)]
+        struct Foo<A> {
+            #[serde(rename = "x")]
+            a: A,
+            #[doc(hidden)]
+            #[serde(skip)]
+            b: u32,
+        }
+"#,
+            BuiltinDeriveExpander::Clone,
+        );
+
+        assert_eq!(
+            expanded,
+            "#[automatically_derived]impl<T0:std::clone::Clone>std::clone::CloneforFoo<T0>{}"
+        );
+    }
+
+    #[test]
+    fn test_debug_expand_is_automatically_derived() {
+        let expanded = expand_builtin_derive(
+            r#"
+        #[derive(Debug)]
+        struct Foo;
+"#,
+            BuiltinDeriveExpander::Debug,
+        );
+
+        assert_eq!(expanded, "#[automatically_derived]impl <>std::fmt::DebugforFoo <>{}");
+    }
 }
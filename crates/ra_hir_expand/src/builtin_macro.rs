@@ -2,10 +2,11 @@
 use crate::db::AstDatabase;
 use crate::{
     ast::{self},
-    name, AstId, CrateId, MacroCallId, MacroDefId, MacroDefKind, TextUnit,
+    name, AstId, AstNode, CrateId, MacroCallId, MacroDefId, MacroDefKind, TextUnit,
 };
 
 use crate::quote;
+use ra_db::RelativePath;
 
 macro_rules! register_builtin {
     ( $(($name:ident, $kind: ident) => $expand:ident),* ) => {
@@ -42,7 +43,12 @@ macro_rules! register_builtin {
         ) -> Option<MacroDefId> {
             let kind = BuiltinFnLikeExpander::by_name(ident)?;
 
-            Some(MacroDefId { krate: Some(krate), ast_id: Some(ast_id), kind: MacroDefKind::BuiltIn(kind) })
+            Some(MacroDefId {
+                krate: Some(krate),
+                ast_id: Some(ast_id),
+                kind: MacroDefKind::BuiltIn(kind),
+                local_inner: false,
+            })
         }
     };
 }
@@ -51,6 +57,7 @@ register_builtin! {
     (column, Column) => column_expand,
     (compile_error, CompileError) => compile_error_expand,
     (file, File) => file_expand,
+    (include, Include) => include_expand,
     (line, Line) => line_expand,
     (stringify, Stringify) => stringify_expand,
     (format_args, FormatArgs) => format_args_expand,
@@ -149,6 +156,38 @@ fn file_expand(
     Ok(expanded)
 }
 
+fn include_expand(
+    db: &dyn AstDatabase,
+    id: MacroCallId,
+    tt: &tt::Subtree,
+) -> Result<tt::Subtree, mbe::ExpandError> {
+    let path = match tt.token_trees.as_slice() {
+        [tt::TokenTree::Leaf(tt::Leaf::Literal(it))] => unquote_str(&it.text)
+            .ok_or_else(|| mbe::ExpandError::BindingError("Must be a string".into()))?,
+        _ => return Err(mbe::ExpandError::BindingError("Must be a string".into())),
+    };
+
+    let anchor = db.lookup_intern_macro(id).kind.file_id().original_file(db);
+    let file_id = db
+        .resolve_relative_path(anchor, RelativePath::new(&path))
+        .ok_or_else(|| mbe::ExpandError::BindingError(format!("Can't find file {}", path)))?;
+
+    // FIXME: the token ids here are unrelated to the ones in the call's token
+    // map, so navigation from the included items is not going to work yet.
+    let source_file = db.parse(file_id).tree();
+    let (subtree, _) = mbe::syntax_node_to_token_tree(source_file.syntax())
+        .ok_or(mbe::ExpandError::ConversionError)?;
+
+    Ok(subtree)
+}
+
+fn unquote_str(text: &str) -> Option<String> {
+    if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+        return None;
+    }
+    Some(text[1..text.len() - 1].to_string())
+}
+
 fn compile_error_expand(
     _db: &dyn AstDatabase,
     _id: MacroCallId,
@@ -171,14 +210,17 @@ fn format_args_expand(
     _id: MacroCallId,
     tt: &tt::Subtree,
 ) -> Result<tt::Subtree, mbe::ExpandError> {
-    // We expand `format_args!("", a1, a2)` to
+    // We expand `format_args!("{} {:?}", a1, a2)` to
     // ```
     // std::fmt::Arguments::new_v1(&[], &[
     //   std::fmt::ArgumentV1::new(&arg1,std::fmt::Display::fmt),
-    //   std::fmt::ArgumentV1::new(&arg2,std::fmt::Display::fmt),
+    //   std::fmt::ArgumentV1::new(&arg2,std::fmt::Debug::fmt),
     // ])
     // ```,
-    // which is still not really correct, but close enough for now
+    // taking the trait of each argument from the first placeholder using it,
+    // which is still not really correct, but close enough for now. Arguments
+    // only used as a width or precision, like `{:w$.p$}` or `{:.*}`, are
+    // passed as counts with `std::fmt::ArgumentV1::from_usize(&arg)`
     let mut args = Vec::new();
     let mut current = Vec::new();
     for tt in tt.token_trees.iter().cloned() {
@@ -198,16 +240,182 @@ fn format_args_expand(
     if args.is_empty() {
         return Err(mbe::ExpandError::NoMatchingRule);
     }
-    let _format_string = args.remove(0);
-    let arg_tts = args.into_iter().flat_map(|arg| {
-        quote! { std::fmt::ArgumentV1::new(&(##arg), std::fmt::Display::fmt), }
-    }.token_trees).collect::<Vec<_>>();
+    let format_string = args.remove(0);
+    let args: Vec<_> = args.into_iter().map(split_named_arg).collect();
+
+    let mut traits = vec![None; args.len()];
+    let mut counts = vec![false; args.len()];
+    let placeholders = match format_string.as_slice() {
+        [tt::TokenTree::Leaf(tt::Leaf::Literal(it))] => unquote_str(&it.text),
+        _ => None,
+    };
+    let mut next_arg = 0;
+    for (arg, spec) in format_placeholders(placeholders.as_deref().unwrap_or("")) {
+        let (count_args, precision_from_next) = format_counts(spec);
+        if precision_from_next {
+            // `{:.*}` takes the precision from the next argument, and the
+            // value from the one after
+            if let Some(it) = counts.get_mut(next_arg) {
+                *it = true;
+            }
+            next_arg += 1;
+        }
+        for count in count_args {
+            if let Some(it) = arg_index(&args, count).and_then(|idx| counts.get_mut(idx)) {
+                *it = true;
+            }
+        }
+        let idx = if arg.is_empty() {
+            next_arg += 1;
+            next_arg - 1
+        } else {
+            match arg_index(&args, arg) {
+                Some(it) => it,
+                None => continue,
+            }
+        };
+        if let Some(it @ None) = traits.get_mut(idx) {
+            *it = Some(format_trait(spec));
+        }
+    }
+
+    let arg_tts = args
+        .into_iter()
+        .zip(traits.into_iter().zip(counts))
+        .flat_map(|((_name, arg), (format_trait, is_count))| {
+            match format_trait {
+                None if is_count => quote! { std::fmt::ArgumentV1::from_usize(&(##arg)), },
+                _ => {
+                    let format_trait = tt::Ident {
+                        text: format_trait.unwrap_or("Display").into(),
+                        id: tt::TokenId::unspecified(),
+                    };
+                    quote! { std::fmt::ArgumentV1::new(&(##arg), std::fmt::#format_trait::fmt), }
+                }
+            }
+            .token_trees
+        })
+        .collect::<Vec<_>>();
     let expanded = quote! {
         std::fmt::Arguments::new_v1(&[], &[##arg_tts])
     };
     Ok(expanded)
 }
 
+/// Splits the name off an argument passed as `name = value`.
+fn split_named_arg(arg: Vec<tt::TokenTree>) -> (Option<String>, Vec<tt::TokenTree>) {
+    let name = match arg.as_slice() {
+        [tt::TokenTree::Leaf(tt::Leaf::Ident(name)), tt::TokenTree::Leaf(tt::Leaf::Punct(eq)), ..]
+            if eq.char == '=' && eq.spacing == tt::Spacing::Alone =>
+        {
+            name.text.to_string()
+        }
+        _ => return (None, arg),
+    };
+    (Some(name), arg[2..].to_vec())
+}
+
+/// Returns the index of an argument referred to by position or by name.
+fn arg_index(args: &[(Option<String>, Vec<tt::TokenTree>)], arg: &str) -> Option<usize> {
+    match arg.parse::<usize>() {
+        Ok(idx) => Some(idx),
+        Err(_) => args.iter().position(|(name, _)| name.as_deref() == Some(arg)),
+    }
+}
+
+/// Returns the arguments a format spec takes its width and precision from, as
+/// in `{:>width$.prec$}`, and whether the precision is `.*`, which takes it
+/// from the next argument.
+fn format_counts(spec: &str) -> (Vec<&str>, bool) {
+    let mut rest = spec;
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next()) {
+        (Some(fill), Some('<')) | (Some(fill), Some('^')) | (Some(fill), Some('>')) => {
+            rest = &rest[fill.len_utf8() + 1..]
+        }
+        (Some('<'), _) | (Some('^'), _) | (Some('>'), _) => rest = &rest[1..],
+        _ => (),
+    }
+    rest = rest.trim_start_matches(&['+', '-', '#'][..]);
+    // `0$` is a width taken from the first argument rather than zero padding
+    if rest.starts_with('0') && !rest[1..].starts_with('$') {
+        rest = &rest[1..];
+    }
+
+    let mut counts = Vec::new();
+    let (width, after) = split_count(rest);
+    counts.extend(width);
+    rest = after;
+    if rest.starts_with(".*") {
+        return (counts, true);
+    }
+    if rest.starts_with('.') {
+        let (precision, _) = split_count(&rest[1..]);
+        counts.extend(precision);
+    }
+    (counts, false)
+}
+
+/// Splits a width or precision off the start of a format spec, returning the
+/// argument it refers to if it is written as `name$` or `0$`.
+fn split_count(text: &str) -> (Option<&str>, &str) {
+    let end = text.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or_else(|| text.len());
+    if text[end..].starts_with('$') {
+        (Some(&text[..end]), &text[end + 1..])
+    } else {
+        (None, &text[end..])
+    }
+}
+
+/// Returns the argument and the spec of each `{argument:spec}` placeholder in
+/// a format string, skipping the `{{` and `}}` escapes.
+fn format_placeholders(text: &str) -> Vec<(&str, &str)> {
+    let mut res = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        let after = &rest[start + 1..];
+        if rest[start..].starts_with("{{") || rest[start..].starts_with("}}") {
+            rest = &after[1..];
+            continue;
+        }
+        if rest[start..].starts_with('}') {
+            rest = after;
+            continue;
+        }
+        let end = match after.find('}') {
+            Some(it) => it,
+            None => break,
+        };
+        let placeholder = &after[..end];
+        let (arg, spec) = match placeholder.find(':') {
+            Some(colon) => (&placeholder[..colon], &placeholder[colon + 1..]),
+            None => (placeholder, ""),
+        };
+        res.push((arg.trim(), spec));
+        rest = &after[end + 1..];
+    }
+    res
+}
+
+/// Returns the formatting trait a format spec asks for. The type is the last
+/// character of the spec, after any fill, alignment, width and precision, as
+/// in `{:>10.2}` or `{:08x}`.
+fn format_trait(spec: &str) -> &'static str {
+    if spec.ends_with('?') {
+        return "Debug";
+    }
+    match spec.chars().last() {
+        Some('x') => "LowerHex",
+        Some('X') => "UpperHex",
+        Some('o') => "Octal",
+        Some('b') => "Binary",
+        Some('e') => "LowerExp",
+        Some('E') => "UpperExp",
+        Some('p') => "Pointer",
+        _ => "Display",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -231,6 +439,7 @@ mod tests {
             krate: Some(CrateId(0)),
             ast_id: Some(AstId::new(file_id.into(), ast_id_map.ast_id(&macro_calls[0]))),
             kind: MacroDefKind::BuiltIn(expander),
+            local_inner: false,
         };
 
         let loc = MacroCallLoc {
@@ -247,6 +456,52 @@ mod tests {
         parsed.text().to_string()
     }
 
+    /// Expands the last macro call, which calls the `macro_rules!` macro
+    /// defined second, and then the call to the built-in macro defined first
+    /// in its expansion.
+    fn expand_builtin_macro_through(s: &str) -> String {
+        let (db, file_id) = TestDB::with_single_file(&s);
+        let parsed = db.parse(file_id);
+        let macro_calls: Vec<_> =
+            parsed.syntax_node().descendants().filter_map(ast::MacroCall::cast).collect();
+
+        let ast_id_map = db.ast_id_map(file_id.into());
+
+        let expander =
+            BuiltinFnLikeExpander::by_name(&macro_calls[0].name().unwrap().as_name()).unwrap();
+
+        let def = MacroDefId {
+            krate: Some(CrateId(0)),
+            ast_id: Some(AstId::new(file_id.into(), ast_id_map.ast_id(&macro_calls[1]))),
+            kind: MacroDefKind::Declarative,
+            local_inner: false,
+        };
+        let call = macro_calls.last().unwrap();
+        let id = def.as_call_id(
+            &db,
+            MacroCallKind::FnLike(AstId::new(file_id.into(), ast_id_map.ast_id(call))),
+        );
+        let expansion = db.parse_or_expand(id.as_file()).unwrap();
+        let inner_call = expansion.descendants().find_map(ast::MacroCall::cast).unwrap();
+
+        let def = MacroDefId {
+            krate: Some(CrateId(0)),
+            ast_id: Some(AstId::new(file_id.into(), ast_id_map.ast_id(&macro_calls[0]))),
+            kind: MacroDefKind::BuiltIn(expander),
+            local_inner: false,
+        };
+        let inner_id = def.as_call_id(
+            &db,
+            MacroCallKind::FnLike(AstId::new(
+                id.as_file(),
+                db.ast_id_map(id.as_file()).ast_id(&inner_call),
+            )),
+        );
+        let parsed = db.parse_or_expand(inner_id.as_file()).unwrap();
+
+        parsed.text().to_string()
+    }
+
     #[test]
     fn test_column_expand() {
         let expanded = expand_builtin_macro(
@@ -356,7 +611,88 @@ mod tests {
 
         assert_eq!(
             expanded,
-            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::new(&(arg1(a,b,c)),std::fmt::Display::fmt),std::fmt::ArgumentV1::new(&(arg2),std::fmt::Display::fmt),])"#
+            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::new(&(arg1(a,b,c)),std::fmt::Display::fmt),std::fmt::ArgumentV1::new(&(arg2),std::fmt::Debug::fmt),])"#
+        );
+    }
+
+    #[test]
+    fn test_format_args_expand_with_width_and_precision() {
+        let expanded = expand_builtin_macro(
+            r#"
+            #[rustc_builtin_macro]
+            macro_rules! format_args {
+                ($fmt:expr) => ({ /* compiler built-in */ });
+                ($fmt:expr, $($args:tt)*) => ({ /* compiler built-in */ })
+            }
+            format_args!("{:>10.2} {:#010x}", x, y);
+            "#,
+        );
+
+        assert_eq!(
+            expanded,
+            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::new(&(x),std::fmt::Display::fmt),std::fmt::ArgumentV1::new(&(y),std::fmt::LowerHex::fmt),])"#
+        );
+    }
+
+    #[test]
+    fn test_format_args_expand_with_named_args() {
+        let expanded = expand_builtin_macro(
+            r#"
+            #[rustc_builtin_macro]
+            macro_rules! format_args {
+                ($fmt:expr) => ({ /* compiler built-in */ });
+                ($fmt:expr, $($args:tt)*) => ({ /* compiler built-in */ })
+            }
+            format_args!("{name:width$} {0:?} {{}}", a, name = b, width = c);
+            "#,
+        );
+
+        assert_eq!(
+            expanded,
+            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::new(&(a),std::fmt::Debug::fmt),std::fmt::ArgumentV1::new(&(b),std::fmt::Display::fmt),std::fmt::ArgumentV1::from_usize(&(c)),])"#
+        );
+    }
+
+    #[test]
+    fn test_format_args_expand_with_precision_from_args() {
+        let expanded = expand_builtin_macro(
+            r#"
+            #[rustc_builtin_macro]
+            macro_rules! format_args {
+                ($fmt:expr) => ({ /* compiler built-in */ });
+                ($fmt:expr, $($args:tt)*) => ({ /* compiler built-in */ })
+            }
+            format_args!("{:.*} {:1$?}", p, x, y);
+            "#,
+        );
+
+        assert_eq!(
+            expanded,
+            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::from_usize(&(p)),std::fmt::ArgumentV1::new(&(x),std::fmt::Display::fmt),std::fmt::ArgumentV1::new(&(y),std::fmt::Debug::fmt),])"#
+        );
+    }
+
+    #[test]
+    fn test_format_expand_with_width_and_precision() {
+        let expanded = expand_builtin_macro_through(
+            r#"
+            #[rustc_builtin_macro]
+            macro_rules! format_args {
+                ($fmt:expr) => ({ /* compiler built-in */ });
+                ($fmt:expr, $($args:tt)*) => ({ /* compiler built-in */ })
+            }
+            macro_rules! format {
+                ($($arg:tt)*) => { std::fmt::format(format_args!($($arg)*)) }
+            }
+            fn main() {
+                let s = format!("{:>10.2}", x);
+            }
+            "#,
+        );
+
+        assert_eq!(
+            expanded,
+            r#"std::fmt::Arguments::new_v1(&[] ,&[std::fmt::ArgumentV1::new(&(x),std::fmt::Display::fmt),])"#
         );
     }
 }
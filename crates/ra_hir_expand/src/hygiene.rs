@@ -1,41 +1,66 @@
 //! This modules handles hygiene information.
 //!
 //! Specifically, `ast` + `Hygiene` allows you to create a `Name`. Note that, at
-//! this moment, this is horribly incomplete and handles only `$crate`.
+//! this moment, this is horribly incomplete and handles only `$crate` and
+//! `local_inner_macros`.
 use either::Either;
 use ra_db::CrateId;
-use ra_syntax::ast;
+use ra_syntax::{
+    ast::{self, AttrsOwner},
+    AstNode, SyntaxToken,
+};
 
 use crate::{
     db::AstDatabase,
     name::{AsName, Name},
-    HirFileId, HirFileIdRepr, MacroDefKind,
+    ExpansionInfo, HirFileId, HirFileIdRepr, InFile, MacroDefKind, Origin,
 };
 
 #[derive(Debug)]
 pub struct Hygiene {
     // This is what `$crate` expands to
     def_crate: Option<CrateId>,
+    // Set if the macro is `#[macro_export(local_inner_macros)]`, which makes
+    // single-segment macro calls written in its definition resolve as
+    // `$crate::name!`
+    local_inner: Option<ExpansionInfo>,
 }
 
 impl Hygiene {
     pub fn new(db: &impl AstDatabase, file_id: HirFileId) -> Hygiene {
-        let def_crate = match file_id.0 {
-            HirFileIdRepr::FileId(_) => None,
+        let (def_crate, local_inner) = match file_id.0 {
+            HirFileIdRepr::FileId(_) => (None, None),
             HirFileIdRepr::MacroFile(macro_file) => {
                 let loc = db.lookup_intern_macro(macro_file.macro_call_id);
                 match loc.def.kind {
-                    MacroDefKind::Declarative => loc.def.krate,
-                    MacroDefKind::BuiltIn(_) => None,
-                    MacroDefKind::BuiltInDerive(_) => None,
+                    MacroDefKind::Declarative => {
+                        let local_inner =
+                            if loc.def.local_inner { file_id.expansion_info(db) } else { None };
+                        (loc.def.krate, local_inner)
+                    }
+                    MacroDefKind::BuiltIn(_) => (None, None),
+                    MacroDefKind::BuiltInDerive(_) => (None, None),
                 }
             }
         };
-        Hygiene { def_crate }
+        Hygiene { def_crate, local_inner }
     }
 
     pub fn new_unhygienic() -> Hygiene {
-        Hygiene { def_crate: None }
+        Hygiene { def_crate: None, local_inner: None }
+    }
+
+    /// The crate a single-segment macro call starting with `token` should be
+    /// resolved in, if the expanded macro is
+    /// `#[macro_export(local_inner_macros)]` and the call is written in its
+    /// definition rather than passed in as an argument.
+    pub fn local_inner_macros(&self, token: &SyntaxToken) -> Option<CrateId> {
+        let info = self.local_inner.as_ref()?;
+        let (_, origin) = info.map_token_up(InFile::new(info.expanded.file_id, token))?;
+        match origin {
+            Origin::Def => self.def_crate,
+            Origin::Call => None,
+        }
     }
 
     // FIXME: this should just return name
@@ -48,3 +73,15 @@ impl Hygiene {
         Either::Left(name_ref.as_name())
     }
 }
+
+/// Whether the macro definition is `#[macro_export(local_inner_macros)]`.
+// FIXME: cfg_attr
+pub fn is_local_inner_macros(macro_def: &ast::MacroCall) -> bool {
+    macro_def.attrs().filter_map(|attr| attr.as_simple_call()).any(|(name, tt)| {
+        name == "macro_export"
+            && tt
+                .syntax()
+                .children_with_tokens()
+                .any(|it| it.as_token().map_or(false, |it| it.text() == "local_inner_macros"))
+    })
+}
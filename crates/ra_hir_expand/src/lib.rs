@@ -20,7 +20,7 @@ use ra_db::{salsa, CrateId, FileId};
 use ra_syntax::{
     algo,
     ast::{self, AstNode},
-    SyntaxNode, SyntaxToken, TextUnit,
+    SmolStr, SyntaxNode, SyntaxToken, TextRange, TextUnit,
 };
 
 use crate::ast_id_map::FileAstId;
@@ -158,6 +158,8 @@ pub struct MacroDefId {
     pub krate: Option<CrateId>,
     pub ast_id: Option<AstId<ast::MacroCall>>,
     pub kind: MacroDefKind,
+    /// Whether the macro is `#[macro_export(local_inner_macros)]`.
+    pub local_inner: bool,
 }
 
 impl MacroDefId {
@@ -268,6 +270,81 @@ impl ExpansionInfo {
             .into_token()?;
         Some((tt.with_value(token), origin))
     }
+
+    /// For `macro_rules!` expansions, returns the index of the rule which
+    /// produced the expansion.
+    pub fn matched_rule_index(&self) -> Option<usize> {
+        match &self.macro_def.0 {
+            db::TokenExpander::MacroRules(it) => it.matching_rule(&self.macro_arg.0),
+            _ => None,
+        }
+    }
+
+    /// For `macro_rules!` expansions, returns the metavariables bound outside
+    /// of repetitions, with the ranges of the arguments they are bound to.
+    pub fn simple_bindings(&self) -> Vec<(SmolStr, InFile<TextRange>)> {
+        let rules = match &self.macro_def.0 {
+            db::TokenExpander::MacroRules(it) => it,
+            _ => return Vec::new(),
+        };
+        let start = self.arg.value.text_range().start();
+        rules
+            .simple_bindings(&self.macro_arg.0)
+            .into_iter()
+            .filter_map(|(name, tt)| {
+                let range = token_tree_range(&tt, &self.macro_arg.1)?;
+                Some((name, self.arg.with_value(range + start)))
+            })
+            .collect()
+    }
+
+    /// For `macro_rules!` expansions, returns the range of the rule which
+    /// produced the expansion.
+    pub fn matched_rule(&self) -> Option<InFile<TextRange>> {
+        let idx = self.matched_rule_index()?;
+
+        // Each rule is a `(lhs) => {rhs}` pair of token trees
+        let mut token_trees =
+            self.def.value.syntax().children().filter_map(ast::TokenTree::cast).skip(2 * idx);
+        let lhs = token_trees.next()?;
+        let rhs = token_trees.next()?;
+        let range = lhs.syntax().text_range().extend_to(&rhs.syntax().text_range());
+        Some(self.def.with_value(range))
+    }
+}
+
+/// The range covered by the tokens of `tt`, relative to the token tree they
+/// were converted from.
+fn token_tree_range(tt: &tt::TokenTree, token_map: &mbe::TokenMap) -> Option<TextRange> {
+    let mut ranges = Vec::new();
+    match tt {
+        tt::TokenTree::Leaf(leaf) => {
+            let id = match leaf {
+                tt::Leaf::Ident(it) => it.id,
+                tt::Leaf::Punct(it) => it.id,
+                tt::Leaf::Literal(it) => it.id,
+            };
+            if let Some(mbe::TokenTextRange::Token(it)) = token_map.range_by_token(id) {
+                ranges.push(it);
+            }
+        }
+        tt::TokenTree::Subtree(subtree) => {
+            if let Some(delimiter) = subtree.delimiter {
+                if let Some(mbe::TokenTextRange::Delimiter(open, close)) =
+                    token_map.range_by_token(delimiter.id)
+                {
+                    ranges.push(open);
+                    ranges.push(close);
+                }
+            }
+            ranges.extend(
+                subtree.token_trees.iter().filter_map(|it| token_tree_range(it, token_map)),
+            );
+        }
+    }
+    ranges
+        .into_iter()
+        .fold(None, |acc, it| Some(acc.map_or(it, |acc: TextRange| acc.extend_to(&it))))
 }
 
 /// `AstId` points to an AST node in any file.
@@ -177,6 +177,7 @@ pub mod known {
         format_args_nl,
         env,
         option_env,
+        include,
         // Builtin derives
         Copy,
         Clone,
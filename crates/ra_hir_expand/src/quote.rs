@@ -95,6 +95,7 @@ macro_rules! __quote {
 
     // Puncts
     // FIXME: Not all puncts are handled
+    ( # ) => {$crate::__quote!(@PUNCT '#')};
     ( -> ) => {$crate::__quote!(@PUNCT '-', '>')};
     ( & ) => {$crate::__quote!(@PUNCT '&')};
     ( , ) => {$crate::__quote!(@PUNCT ',')};
@@ -1,287 +1,5587 @@
 //! This modules implements "expand macro" functionality in the IDE
 
-use hir::Semantics;
-use ra_ide_db::RootDatabase;
+use std::{
+    iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hir::{HasSource, ModuleDef, PathResolution, ScopeDef, Semantics};
+use ra_cfg::CfgOptions;
+use ra_ide_db::{LineIndexDatabase, RootDatabase};
 use ra_syntax::{
-    algo::{find_node_at_offset, replace_descendants},
-    ast, AstNode, NodeOrToken, SyntaxElement, SyntaxKind, SyntaxNode, WalkEvent, T,
+    algo::{
+        find_node_at_offset, insert_children, non_trivia_sibling, replace_children,
+        replace_descendants, InsertPosition,
+    },
+    ast::{self, make, AttrsOwner, ModuleItemOwner, NameOwner},
+    AstNode, Direction, NodeOrToken, SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken, TextRange,
+    TextUnit, WalkEvent, T,
 };
 use rustc_hash::FxHashMap;
 
-use crate::FilePosition;
+use crate::{
+    syntax_highlighting::{highlight_token_syntactically, HighlightTag},
+    FileId, FilePosition, FileRange,
+};
 
 pub struct ExpandedMacro {
     pub name: String,
+    /// The called path with the `!` attached, like `serde_json::json!`.
+    pub qualified_name: String,
+    pub expansion: String,
+    /// The `macro_rules!` rule used to expand the call.
+    pub matched_rule_range: Option<FileRange>,
+    /// Names of the top-level items defined by the expansion.
+    pub introduced_names: Vec<String>,
+    /// Calls the expansion still contains because expanding them would have
+    /// gone over the `ExpansionLimits`, with their ranges in `expansion`.
+    pub skipped_calls: Vec<(TextRange, String)>,
+    /// The ranges in `expansion` of the tokens written in the source, with
+    /// the ranges they were written at. Empty if `ExpandMacroConfig::formatter`
+    /// rewrote the expansion.
+    pub source_map: Vec<(TextRange, FileRange)>,
+    /// Syntax errors in `expansion` when it is parsed again, which point to
+    /// bugs in the renderer. Only filled in with `ExpandMacroConfig::validate`.
+    pub render_parse_errors: Vec<String>,
+    /// Locals the macro binds under a name also passed to it, which hygiene
+    /// keeps apart but `expansion` doesn't. Only filled in with
+    /// `ExpandMacroConfig::report_hygiene_collisions`.
+    pub hygiene_collisions: Vec<String>,
+    /// The text of the expanded tree as is, without the whitespace inserted
+    /// for rendering. Only set with `ExpandMacroConfig::include_raw`.
+    pub raw: Option<String>,
+}
+
+/// The expansions of a macro call and of the calls it produces, in the order
+/// they are expanded.
+#[derive(Debug)]
+pub struct ExpansionReport {
+    pub levels: Vec<ExpansionLevel>,
+}
+
+#[derive(Debug)]
+pub struct ExpansionLevel {
+    /// How many expansions the call is produced by, 0 for the call at the
+    /// cursor.
+    pub depth: usize,
+    pub name: String,
+    /// The index of the `macro_rules!` rule used to expand the call.
+    pub rule_index: Option<usize>,
+    /// The expansion of this call alone, with the calls it produces left
+    /// unexpanded.
     pub expansion: String,
 }
 
-pub(crate) fn expand_macro(db: &RootDatabase, position: FilePosition) -> Option<ExpandedMacro> {
+#[derive(Clone, Default)]
+pub struct ExpandMacroConfig {
+    /// Post-processes the rendered expansion if it consists of items, for
+    /// example by running `rustfmt` on it. Returning `None` keeps the output
+    /// of the built-in renderer.
+    pub formatter: Option<Arc<dyn Fn(&str) -> Option<String> + Send + Sync>>,
+    /// Replaces the bodies of generated functions with `{ /* ... */ }`.
+    pub signatures_only: bool,
+    /// Replaces the bodies of generated functions nested in more than this
+    /// many functions, counting their own, with `{ /* ... */ }`.
+    pub max_body_depth: Option<usize>,
+    /// Replaces blocks that only hold another block, like `{ { { x } } }`,
+    /// with the innermost block and a comment saying how many were removed.
+    pub collapse_redundant_blocks: bool,
+    /// Fills in `ExpandedMacro::raw`, for debugging the renderer.
+    pub include_raw: bool,
+    /// Parses the rendered expansion again to fill in
+    /// `ExpandedMacro::render_parse_errors`.
+    pub validate: bool,
+    /// Puts each pattern of a match arm, and each import of a `use` group, on
+    /// its own line when they would make the line longer than this.
+    pub max_width: Option<usize>,
+    /// Evaluates the `#[cfg]` attributes in the expansion with these options,
+    /// like `target_os = "windows"`, leaving out what they disable. Without
+    /// them the attributes are kept as they are.
+    pub cfg: Option<CfgOptions>,
+    /// Adds a `/* $x = "..." */` comment after each place a metavariable of
+    /// the expanded call was substituted, with the text it was bound to.
+    pub annotate_substitutions: bool,
+    pub render_mode: RenderMode,
+    /// Fills in `ExpandedMacro::hygiene_collisions`.
+    pub report_hygiene_collisions: bool,
+    pub limits: ExpansionLimits,
+    pub partial: PartialExpansion,
+}
+
+/// A path the expansion refers to, as it would be imported at the crate root.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequiredImport {
+    pub path: String,
+    /// Whether the item is in the prelude, and so in scope without an import.
+    pub from_prelude: bool,
+}
+
+/// What to do with an expansion that doesn't parse completely, as when a
+/// macro produces tokens that don't form valid items or an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartialExpansion {
+    /// Render the tree as it was parsed.
+    Render,
+    /// Render the tree with a `/* partial expansion */` comment in front.
+    Mark,
+    /// Return an error instead of an expansion.
+    Fail,
+}
+
+impl Default for PartialExpansion {
+    fn default() -> PartialExpansion {
+        PartialExpansion::Render
+    }
+}
+
+/// How the expansion is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    /// The built-in layout, keeping the source layout of `#[rustfmt::skip]`
+    /// items, then `ExpandMacroConfig::formatter` if there is one.
+    Normal,
+    /// The built-in layout alone, which only depends on the tokens of the
+    /// expansion, for comparing expansions. The source layout and comments
+    /// of `#[rustfmt::skip]` items, `ExpandMacroConfig::formatter` and
+    /// `ExpandMacroConfig::max_width` are ignored.
+    Canonical,
+}
+
+impl Default for RenderMode {
+    fn default() -> RenderMode {
+        RenderMode::Normal
+    }
+}
+
+/// Bounds how much work expanding a single macro call may take. Nested calls
+/// that would go over a limit are left unexpanded.
+#[derive(Clone, Copy, Debug)]
+pub struct ExpansionLimits {
+    /// How many levels of macro calls produced by the expansion are expanded.
+    pub depth: usize,
+    /// How many tokens all of the expansions may have together.
+    pub tokens: usize,
+    /// How long to keep expanding nested calls.
+    pub time: Option<Duration>,
+}
+
+impl Default for ExpansionLimits {
+    fn default() -> ExpansionLimits {
+        ExpansionLimits { depth: 64, tokens: 65536, time: None }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExpandMacroError(String);
+
+impl std::fmt::Display for ExpandMacroError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Can't expand macro: {}", self.0)
+    }
+}
+
+impl std::error::Error for ExpandMacroError {}
+
+/// How many times in a row the expansions of nested calls may grow by the same
+/// number of tokens before we assume they would keep growing forever.
+const GROWTH_STEPS: usize = 4;
+
+struct Budget {
+    depth: usize,
+    tokens: usize,
+    deadline: Option<Instant>,
+    /// Token counts of the expansions on the current chain of nested calls.
+    chain: Vec<usize>,
+    grows_without_bound: bool,
+    /// The text of the calls left unexpanded because of the limits.
+    unexpanded: Vec<String>,
+    /// The expansion of each call, as `Semantics` knows it.
+    expansions: Vec<SyntaxNode>,
+}
+
+impl Budget {
+    fn new(limits: &ExpansionLimits) -> Budget {
+        Budget {
+            depth: limits.depth,
+            tokens: limits.tokens,
+            deadline: limits.time.map(|it| Instant::now() + it),
+            chain: Vec::new(),
+            grows_without_bound: false,
+            unexpanded: Vec::new(),
+            expansions: Vec::new(),
+        }
+    }
+
+    fn skip(&mut self, macro_call: &ast::MacroCall) {
+        self.unexpanded.push(macro_call.syntax().text().to_string());
+    }
+
+    /// Takes the tokens of the expansion of `macro_call` at `depth` out of the
+    /// budget. Returns `false` if the call shouldn't be expanded, because the
+    /// tokens run out or the expansions keep growing.
+    fn spend(&mut self, macro_call: &ast::MacroCall, depth: usize, tokens: usize) -> bool {
+        self.tokens = match self.tokens.checked_sub(tokens) {
+            Some(it) => it,
+            None => {
+                self.skip(macro_call);
+                return false;
+            }
+        };
+        if self.grows_steadily(depth, tokens) {
+            self.grows_without_bound = true;
+            return false;
+        }
+        true
+    }
+
+    fn is_out_of_time(&self) -> bool {
+        self.deadline.map_or(false, |it| Instant::now() >= it)
+    }
+
+    /// Records the size of an expansion at `depth` and checks whether the
+    /// expansions leading to it keep growing by the same amount.
+    fn grows_steadily(&mut self, depth: usize, tokens: usize) -> bool {
+        self.chain.truncate(depth);
+        self.chain.push(tokens);
+        if self.chain.len() <= GROWTH_STEPS {
+            return false;
+        }
+        let steps: Vec<Option<usize>> = self.chain[self.chain.len() - GROWTH_STEPS - 1..]
+            .windows(2)
+            .map(|it| it[1].checked_sub(it[0]))
+            .collect();
+        match steps[0] {
+            Some(step) if step > 0 => steps.iter().all(|&it| it == Some(step)),
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn expand_macro(
+    db: &RootDatabase,
+    position: FilePosition,
+    config: &ExpandMacroConfig,
+) -> Result<Option<ExpandedMacro>, ExpandMacroError> {
     let sema = Semantics::new(db);
-    let file = sema.parse(position.file_id);
-    let name_ref = find_node_at_offset::<ast::NameRef>(file.syntax(), position.offset)?;
-    let mac = name_ref.syntax().ancestors().find_map(ast::MacroCall::cast)?;
+    match find_complete_macro_call(&sema, position)? {
+        Some(mac) => expand_macro_call(&sema, &mac, config),
+        None => Ok(None),
+    }
+}
+
+/// A macro call expanded recursively within the `ExpansionLimits`.
+struct Expansion {
+    node: SyntaxNode,
+    /// Where in the source each token of `node` was written, in order.
+    origins: Vec<Option<FileRange>>,
+    /// The text of the calls left unexpanded because of the limits.
+    unexpanded: Vec<String>,
+    /// The expansion of each call on its own, in the order the calls were
+    /// expanded. Unlike `node`, `Semantics` knows these, so the paths in them
+    /// can be resolved.
+    expansions: Vec<SyntaxNode>,
+}
+
+/// Finds the macro call at `position` and expands it within `limits`.
+fn expand_macro_at(
+    sema: &Semantics<RootDatabase>,
+    position: FilePosition,
+    limits: &ExpansionLimits,
+) -> Result<Option<(ast::MacroCall, Expansion)>, ExpandMacroError> {
+    let mac = match find_complete_macro_call(sema, position)? {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    Ok(expand_within_limits(sema, &mac, limits)?.map(|it| (mac, it)))
+}
+
+/// Expands `mac` and the calls it produces within `limits`. Fails if the
+/// expansions appear to grow without bound.
+fn expand_within_limits(
+    sema: &Semantics<RootDatabase>,
+    mac: &ast::MacroCall,
+    limits: &ExpansionLimits,
+) -> Result<Option<Expansion>, ExpandMacroError> {
+    let mut budget = Budget::new(limits);
+    let res = expand_macro_recur_with_origins(sema, mac, &mut budget, 0);
+    if budget.grows_without_bound {
+        return Err(ExpandMacroError("expansion appears to grow without bound".to_string()));
+    }
+    Ok(res.map(|(node, origins)| Expansion {
+        node,
+        origins,
+        unexpanded: budget.unexpanded,
+        expansions: budget.expansions,
+    }))
+}
+
+fn expand_macro_call(
+    sema: &Semantics<RootDatabase>,
+    mac: &ast::MacroCall,
+    config: &ExpandMacroConfig,
+) -> Result<Option<ExpandedMacro>, ExpandMacroError> {
+    let name = match macro_name(mac) {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let qualified_name = match qualified_name(mac) {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let Expansion { node: mut expanded, origins, mut unexpanded, .. } =
+        match expand_within_limits(sema, mac, &config.limits)? {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+    let unrewritten = expanded.clone();
+    let is_partial = expanded.descendants().any(|it| it.kind() == SyntaxKind::ERROR);
+    if is_partial && config.partial == PartialExpansion::Fail {
+        return Err(ExpandMacroError("expansion is not valid syntax".to_string()));
+    }
+    expanded = parenthesize_operands(expanded);
+    if let Some(options) = &config.cfg {
+        expanded = strip_cfg_disabled(expanded, options);
+    }
+    if config.signatures_only {
+        expanded = collapse_fn_bodies(expanded, 0);
+    } else if let Some(max_depth) = config.max_body_depth {
+        expanded = collapse_fn_bodies(expanded, max_depth);
+    }
+    if config.collapse_redundant_blocks {
+        expanded = collapse_redundant_blocks(expanded);
+    }
+    let matched_rule_range = sema.matched_macro_rule(mac);
+    let introduced_names = introduced_names(&expanded);
 
-    let expanded = expand_macro_recur(&sema, &mac)?;
+    let is_items = expanded.kind() == SyntaxKind::MACRO_ITEMS;
+    let raw = if config.include_raw { Some(expanded.text().to_string()) } else { None };
 
     // FIXME:
     // macro expansion may lose all white space information
     // But we hope someday we can use ra_fmt for that
-    let expansion = insert_whitespaces(expanded);
-    Some(ExpandedMacro { name: name_ref.text().to_string(), expansion })
+    let is_canonical = config.render_mode == RenderMode::Canonical;
+    let (mut expansion, mut token_ranges) = insert_whitespaces_with_ranges(
+        expanded.clone(),
+        &|token| token.text().to_string(),
+        config.max_width.filter(|_| !is_canonical),
+        !is_canonical,
+    );
+    let token_origins = realign_origins(&unrewritten, &origins, &expanded);
+    if config.annotate_substitutions {
+        expansion =
+            annotate_substitutions(sema, mac, &expansion, &mut token_ranges, &token_origins);
+    }
+    let hygiene_collisions = if config.report_hygiene_collisions {
+        hygiene_collisions(sema, mac, &expanded, &token_origins)
+    } else {
+        Vec::new()
+    };
+    let mut skipped_calls = skipped_calls(&expanded, &token_ranges, &mut unexpanded);
+    let mut source_map: Vec<(TextRange, FileRange)> = token_origins
+        .into_iter()
+        .filter_map(|(token, origin)| Some((*token_ranges.get(&token)?, origin)))
+        .collect();
+    source_map.sort_by_key(|(range, _)| range.start());
+    let formatter = config.formatter.as_ref().filter(|_| is_items && !is_canonical);
+    let expansion = match formatter.and_then(|it| it(&expansion)) {
+        Some(formatted) => {
+            skipped_calls = find_again(&expansion, &formatted, skipped_calls);
+            source_map.clear();
+            formatted
+        }
+        None => expansion,
+    };
+    let render_parse_errors =
+        if config.validate { parse_errors(expanded.kind(), &expansion) } else { Vec::new() };
+    let expansion = if is_partial && config.partial == PartialExpansion::Mark {
+        let note = "/* partial expansion */\n";
+        let shift = TextUnit::of_str(note);
+        skipped_calls.iter_mut().for_each(|(range, _)| *range = *range + shift);
+        source_map.iter_mut().for_each(|(range, _)| *range = *range + shift);
+        format!("{}{}", note, expansion)
+    } else {
+        expansion
+    };
+    Ok(Some(ExpandedMacro {
+        name,
+        qualified_name,
+        expansion,
+        matched_rule_range,
+        introduced_names,
+        skipped_calls,
+        source_map,
+        render_parse_errors,
+        hygiene_collisions,
+        raw,
+    }))
+}
+
+/// Adds a `/* $x = "..." */` comment after each run of tokens substituted for
+/// a metavariable of `mac`, moving the ranges of the tokens after it.
+fn annotate_substitutions(
+    sema: &Semantics<RootDatabase>,
+    mac: &ast::MacroCall,
+    expansion: &str,
+    token_ranges: &mut FxHashMap<SyntaxToken, TextRange>,
+    token_origins: &FxHashMap<SyntaxToken, FileRange>,
+) -> String {
+    let bindings: Vec<(String, FileRange, String)> = sema
+        .macro_bindings(mac)
+        .into_iter()
+        .map(|(name, range)| {
+            let text = sema.parse(range.file_id).syntax().text().slice(range.range).to_string();
+            (name, range, text)
+        })
+        .collect();
+    let binding_at = |token: &SyntaxToken| {
+        let origin = token_origins.get(token)?;
+        let idx = bindings.iter().position(|(_, range, _)| {
+            range.file_id == origin.file_id && origin.range.is_subrange(&range.range)
+        })?;
+        Some((idx, origin.range))
+    };
+
+    let mut tokens: Vec<SyntaxToken> = token_ranges.keys().cloned().collect();
+    tokens.sort_by_key(|it| token_ranges[it].start());
+    let mut notes = Vec::new();
+    for (idx, token) in tokens.iter().enumerate() {
+        let (binding, origin) = match binding_at(token) {
+            Some(it) => it,
+            None => continue,
+        };
+        // A substitution goes on as long as the tokens follow each other in
+        // the argument, so that `$x $x` gets two comments
+        let goes_on = match tokens.get(idx + 1).and_then(|it| binding_at(it)) {
+            Some((next, next_origin)) => next == binding && next_origin.start() >= origin.end(),
+            None => false,
+        };
+        if !goes_on {
+            let (name, _, text) = &bindings[binding];
+            notes.push((token_ranges[token].end(), format!(" /* ${} = {:?} */", name, text)));
+        }
+    }
+
+    let shift = |offset: TextUnit, inclusive: bool| -> TextUnit {
+        notes
+            .iter()
+            .filter(|(at, _)| *at < offset || inclusive && *at == offset)
+            .map(|(_, note)| TextUnit::of_str(note))
+            .sum()
+    };
+    for range in token_ranges.values_mut() {
+        let start = range.start() + shift(range.start(), true);
+        let end = range.end() + shift(range.end(), false);
+        *range = TextRange::from_to(start, end);
+    }
+    let mut res = String::new();
+    let mut last = 0;
+    for (at, note) in notes.iter() {
+        res += &expansion[last..at.to_usize()];
+        res += note;
+        last = at.to_usize();
+    }
+    res += &expansion[last..];
+    res
+}
+
+/// Describes the locals the macro binds in `expanded` under the same name as
+/// an identifier passed to it. Only arguments bound outside of repetitions
+/// are looked at.
+fn hygiene_collisions(
+    sema: &Semantics<RootDatabase>,
+    mac: &ast::MacroCall,
+    expanded: &SyntaxNode,
+    token_origins: &FxHashMap<SyntaxToken, FileRange>,
+) -> Vec<String> {
+    let arguments: Vec<FileRange> =
+        sema.macro_bindings(mac).into_iter().map(|(_, range)| range).collect();
+    let is_passed = |origin: &FileRange| {
+        arguments
+            .iter()
+            .any(|it| it.file_id == origin.file_id && origin.range.is_subrange(&it.range))
+    };
+    let (passed, written): (Vec<SyntaxToken>, Vec<SyntaxToken>) = expanded
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.kind() == SyntaxKind::IDENT && token_origins.contains_key(it))
+        .partition(|it| is_passed(&token_origins[it]));
+
+    let mut res = Vec::new();
+    for token in written {
+        let is_local =
+            token.parent().parent().map_or(false, |it| it.kind() == SyntaxKind::BIND_PAT);
+        if !is_local || passed.iter().all(|it| it.text() != token.text()) {
+            continue;
+        }
+        let note = format!("`{0}` bound by the macro is not the `{0}` passed to it", token.text());
+        if !res.contains(&note) {
+            res.push(note);
+        }
+    }
+    res
+}
+
+/// Parses the rendered expansion of a tree of the given kind, returning the
+/// syntax errors. Expansions other than items, statements and expressions
+/// aren't checked.
+fn parse_errors(kind: SyntaxKind, expansion: &str) -> Vec<String> {
+    let text = if kind == SyntaxKind::MACRO_ITEMS {
+        expansion.to_string()
+    } else if kind == SyntaxKind::MACRO_STMTS || ast::Expr::can_cast(kind) {
+        format!("fn f() {{\n{}\n}}", expansion)
+    } else {
+        return Vec::new();
+    };
+    ast::SourceFile::parse(&text).errors().iter().map(|it| it.to_string()).collect()
+}
+
+/// Finds the calls in `expanded` that were left unexpanded because of the
+/// limits, with their ranges in the rendered expansion.
+fn skipped_calls(
+    expanded: &SyntaxNode,
+    token_ranges: &FxHashMap<SyntaxToken, TextRange>,
+    unexpanded: &mut Vec<String>,
+) -> Vec<(TextRange, String)> {
+    expanded
+        .descendants()
+        .filter_map(ast::MacroCall::cast)
+        .filter_map(|call| {
+            let text = call.syntax().text().to_string();
+            let idx = unexpanded.iter().position(|it| *it == text)?;
+            unexpanded.swap_remove(idx);
+            let start = token_ranges.get(&call.syntax().first_token()?)?.start();
+            let end = token_ranges.get(&call.syntax().last_token()?)?.end();
+            Some((TextRange::from_to(start, end), macro_name(&call)?))
+        })
+        .collect()
+}
+
+/// Looks for the skipped calls again after the formatter rewrote the
+/// expansion, dropping the ones it changed.
+fn find_again(
+    expansion: &str,
+    formatted: &str,
+    skipped_calls: Vec<(TextRange, String)>,
+) -> Vec<(TextRange, String)> {
+    let mut offset = 0;
+    skipped_calls
+        .into_iter()
+        .filter_map(|(range, name)| {
+            let text = &expansion[range];
+            let start = offset + formatted[offset..].find(text)?;
+            offset = start + text.len();
+            Some((TextRange::offset_len(TextUnit::from_usize(start), range.len()), name))
+        })
+        .collect()
+}
+
+fn macro_name(mac: &ast::MacroCall) -> Option<String> {
+    let name_ref = mac.path()?.segment()?.name_ref()?;
+    // A macro named with a raw identifier, like `r#match!`, is called `match`
+    Some(name_ref.text().trim_start_matches("r#").to_string())
+}
+
+/// Expands the macro call at `position` one level at a time, recording which
+/// rule each call is expanded with.
+pub(crate) fn expand_macro_report(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<ExpansionReport>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let mac = match find_complete_macro_call(&sema, position)? {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+
+    let mut budget = Budget::new(&ExpansionLimits::default());
+    let mut levels = Vec::new();
+    collect_levels(&sema, &mac, &mut budget, 0, &mut levels);
+    if budget.grows_without_bound {
+        return Err(ExpandMacroError("expansion appears to grow without bound".to_string()));
+    }
+    Ok(Some(ExpansionReport { levels }))
+}
+
+fn collect_levels(
+    sema: &Semantics<RootDatabase>,
+    macro_call: &ast::MacroCall,
+    budget: &mut Budget,
+    depth: usize,
+    acc: &mut Vec<ExpansionLevel>,
+) {
+    let (name, expanded) = match (macro_name(macro_call), sema.expand(macro_call)) {
+        (Some(name), Some(expanded)) => (name, expanded),
+        _ => return,
+    };
+    let tokens = expanded.descendants_with_tokens().filter(|it| it.as_token().is_some()).count();
+    if !budget.spend(macro_call, depth, tokens) {
+        return;
+    }
+    acc.push(ExpansionLevel {
+        depth,
+        name,
+        rule_index: sema.matched_macro_rule_index(macro_call),
+        expansion: insert_whitespaces(expanded.clone(), &|token| token.text().to_string()),
+    });
+    if depth >= budget.depth {
+        return;
+    }
+    for child in expanded.descendants().filter_map(ast::MacroCall::cast) {
+        if budget.is_out_of_time() || budget.grows_without_bound {
+            return;
+        }
+        collect_levels(sema, &child, budget, depth + 1, acc);
+    }
+}
+
+/// Returns the source text of the macro call at `position` together with its
+/// rendered expansion, so that they can be shown side by side.
+pub(crate) fn expand_macro_side_by_side(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<(String, String)>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let mac = match find_complete_macro_call(&sema, position)? {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let res = expand_macro_call(&sema, &mac, &ExpandMacroConfig::default())?
+        .map(|expanded| (mac.syntax().text().to_string(), expanded.expansion));
+    Ok(res)
+}
+
+/// Returns the source of the macro definition the call at `position` resolves
+/// to, together with the expansion of the call.
+pub(crate) fn expand_macro_with_definition(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<(String, ExpandedMacro)>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let mac = match find_complete_macro_call(&sema, position)? {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let def = match sema.resolve_macro_call(&mac) {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let res = expand_macro_call(&sema, &mac, &ExpandMacroConfig::default())?
+        .map(|expanded| (def.source(db).value.syntax().text().to_string(), expanded));
+    Ok(res)
+}
+
+/// Renders the desugaring of the `?` operator at `position`, which the
+/// compiler expands much like a macro.
+pub(crate) fn expand_try_operator(db: &RootDatabase, position: FilePosition) -> Option<String> {
+    let sema = Semantics::new(db);
+    let file = sema.parse(position.file_id);
+    let try_expr = find_node_at_offset::<ast::TryExpr>(file.syntax(), position.offset)?;
+    let expr = try_expr.expr()?;
+
+    let desugared = make::expr_from_text(&format!(
+        "match ::core::ops::Try::branch({}) {{
+            ::core::ops::ControlFlow::Continue(val) => val,
+            ::core::ops::ControlFlow::Break(residual) => {{
+                return ::core::ops::FromResidual::from_residual(residual)
+            }}
+        }}",
+        expr.syntax().text()
+    ));
+    Some(insert_whitespaces(desugared.syntax().clone(), &|token| token.text().to_string()))
+}
+
+/// Like `find_macro_call`, but fails if the call at `position` is still being
+/// typed.
+fn find_complete_macro_call(
+    sema: &Semantics<RootDatabase>,
+    position: FilePosition,
+) -> Result<Option<ast::MacroCall>, ExpandMacroError> {
+    let file = sema.parse(position.file_id);
+    if let Some(mac) = find_node_at_offset::<ast::MacroCall>(file.syntax(), position.offset) {
+        if !is_complete(&mac) {
+            return Err(ExpandMacroError("macro call is incomplete".to_string()));
+        }
+    }
+    Ok(find_macro_call(sema, &file, position))
+}
+
+/// Finds the innermost macro call at `position` that can be expanded.
+///
+/// A call in the arguments of another call is just a token tree in the source,
+/// so it is looked up in the expansion of the outer call. Calls that can't be
+/// expanded are skipped in favor of the calls around them.
+fn find_macro_call(
+    sema: &Semantics<RootDatabase>,
+    file: &ast::SourceFile,
+    position: FilePosition,
+) -> Option<ast::MacroCall> {
+    let token = file
+        .syntax()
+        .token_at_offset(position.offset)
+        .max_by_key(|it| it.kind() == SyntaxKind::IDENT)?;
+    let token = sema.descend_into_macros(token);
+    sema.ancestors_with_macros(token.parent())
+        .filter_map(ast::MacroCall::cast)
+        .find(|it| sema.expand(it).is_some())
+}
+
+/// Whether the token tree of the macro call is closed. It isn't while the call
+/// is still being typed, and expanding such a call is meaningless.
+fn is_complete(macro_call: &ast::MacroCall) -> bool {
+    let tt = match macro_call.token_tree() {
+        Some(it) => it,
+        None => return false,
+    };
+    let mut tokens = tt
+        .syntax()
+        .children_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| !it.kind().is_trivia())
+        .map(|it| it.kind());
+    match (tokens.next(), tokens.last()) {
+        (Some(T!['(']), Some(T![')']))
+        | (Some(T!['[']), Some(T![']']))
+        | (Some(T!['{']), Some(T!['}'])) => true,
+        _ => false,
+    }
+}
+
+/// Renders each item the macro call at `position` expands to on its own,
+/// together with the name of the item, which is empty for items without one,
+/// like impls.
+pub(crate) fn expand_macro_items(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Vec<(String, String)>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let expanded = match expand_macro_at(&sema, position, &ExpansionLimits::default())? {
+        Some((_, it)) => parenthesize_operands(it.node),
+        None => return Ok(Vec::new()),
+    };
+    let items = top_level_items(&expanded)
+        .into_iter()
+        .map(|item| {
+            let name = item_name(&item).unwrap_or_default();
+            (name, insert_whitespaces(item, &|token| token.text().to_string()))
+        })
+        .collect();
+    Ok(items)
+}
+
+/// Renders the recursive expansion of the macro call at `position` with ANSI
+/// color codes, for terminal consumers.
+pub(crate) fn expand_macro_ansi(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<String>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let res = expand_macro_at(&sema, position, &ExpansionLimits::default())?
+        .map(|(_, it)| insert_whitespaces(parenthesize_operands(it.node), &ansi_paint));
+    Ok(res)
+}
+
+/// Renders the recursive expansion of the macro call at `position` as a Rust
+/// code block for documentation. Expansions other than items are put in a
+/// `main` function, whose lines are hidden from the rendered docs.
+pub(crate) fn expand_macro_doctest(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<String>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let expanded = match expand_macro_at(&sema, position, &ExpansionLimits::default())? {
+        Some((_, it)) => it.node,
+        None => return Ok(None),
+    };
+    let is_items = expanded.kind() == SyntaxKind::MACRO_ITEMS;
+    let expansion =
+        insert_whitespaces(parenthesize_operands(expanded), &|token| token.text().to_string());
+    let res = if is_items {
+        format!("```rust\n{}\n```", expansion)
+    } else {
+        format!("```rust\n# fn main() {{\n{}\n# }}\n```", expansion)
+    };
+    Ok(Some(res))
+}
+
+/// Serializes the recursive expansion of the macro call at `position` as a
+/// JSON tree, for tools that walk it without parsing the rendered text. Nodes
+/// are written as `{"kind":"FN_DEF","children":[...]}` and tokens as
+/// `{"kind":"IDENT","text":"foo"}`, leaving out whitespace.
+pub(crate) fn expand_macro_ast_json(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Option<String>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let expanded = match expand_macro_at(&sema, position, &ExpansionLimits::default())? {
+        Some((_, it)) => it.node,
+        None => return Ok(None),
+    };
+    let mut res = String::new();
+    write_json(&mut res, expanded.into());
+    Ok(Some(res))
+}
+
+fn write_json(buf: &mut String, element: SyntaxElement) {
+    match element {
+        NodeOrToken::Node(node) => {
+            *buf += &format!("{{\"kind\":\"{:?}\",\"children\":[", node.kind());
+            let children =
+                node.children_with_tokens().filter(|it| it.kind() != SyntaxKind::WHITESPACE);
+            for (idx, child) in children.enumerate() {
+                if idx > 0 {
+                    buf.push(',');
+                }
+                write_json(buf, child);
+            }
+            *buf += "]}";
+        }
+        NodeOrToken::Token(token) => {
+            *buf += &format!("{{\"kind\":\"{:?}\",\"text\":\"", token.kind());
+            for c in token.text().chars() {
+                match c {
+                    '"' => *buf += "\\\"",
+                    '\\' => *buf += "\\\\",
+                    '\n' => *buf += "\\n",
+                    '\r' => *buf += "\\r",
+                    '\t' => *buf += "\\t",
+                    c if (c as u32) < 0x20 => *buf += &format!("\\u{:04x}", c as u32),
+                    c => buf.push(c),
+                }
+            }
+            *buf += "\"}";
+        }
+    }
+}
+
+/// Lists the macro calls starting on the given zero-based line, with their
+/// ranges and names. `macro_rules!` definitions are not included.
+pub(crate) fn macro_calls_on_line(
+    db: &RootDatabase,
+    file_id: FileId,
+    line: u32,
+) -> Vec<(TextRange, String)> {
+    let sema = Semantics::new(db);
+    let file = sema.parse(file_id);
+    let line_index = db.line_index(file_id);
+    file.syntax()
+        .descendants()
+        .filter_map(ast::MacroCall::cast)
+        .filter(|mac| mac.name().is_none())
+        .filter(|mac| line_index.line_col(mac.syntax().text_range().start()).line == line)
+        .filter_map(|mac| Some((mac.syntax().text_range(), macro_name(&mac)?)))
+        .collect()
+}
+
+fn ansi_paint(token: &SyntaxToken) -> String {
+    let color = match highlight_token_syntactically(token) {
+        Some(HighlightTag::KEYWORD)
+        | Some(HighlightTag::KEYWORD_CONTROL)
+        | Some(HighlightTag::KEYWORD_UNSAFE) => "35",
+        Some(HighlightTag::LITERAL_STRING)
+        | Some(HighlightTag::LITERAL_CHAR)
+        | Some(HighlightTag::LITERAL_BYTE) => "32",
+        Some(HighlightTag::LITERAL_NUMERIC) => "36",
+        Some(HighlightTag::TYPE) | Some(HighlightTag::TYPE_PARAM) => "33",
+        Some(HighlightTag::TYPE_LIFETIME) => "34",
+        Some(HighlightTag::LITERAL_COMMENT) => "90",
+        _ => return token.text().to_string(),
+    };
+    format!("\x1b[{}m{}\x1b[0m", color, token.text())
+}
+
+/// Returns the paths, relative to the crate root, of the items the expansion
+/// of the macro call at `position` refers to, so that they can be imported
+/// wherever the expansion is pasted.
+pub(crate) fn expansion_required_imports(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Vec<String>, ExpandMacroError> {
+    Ok(expansion_imports(db, position)?.into_iter().map(|it| it.path).collect())
+}
+
+pub(crate) fn expansion_imports(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Vec<RequiredImport>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let (mac, expansion) = match expand_macro_at(&sema, position, &ExpansionLimits::default())? {
+        Some(it) => it,
+        None => return Ok(Vec::new()),
+    };
+    let krate = match sema.scope(mac.syntax()).module() {
+        Some(it) => it.krate(),
+        None => return Ok(Vec::new()),
+    };
+    let root = match krate.root_module(db) {
+        Some(it) => it,
+        None => return Ok(Vec::new()),
+    };
+    let prelude: Vec<ModuleDef> = krate
+        .prelude(db)
+        .map(|it| it.scope(db))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(_, def)| match def {
+            ScopeDef::ModuleDef(it) => Some(it),
+            _ => None,
+        })
+        .collect();
+
+    let mut res = Vec::new();
+    for expanded in &expansion.expansions {
+        collect_required_imports(&sema, expanded, root, &prelude, &mut res);
+    }
+    Ok(res)
+}
+
+fn collect_required_imports(
+    sema: &Semantics<RootDatabase>,
+    expanded: &SyntaxNode,
+    root: hir::Module,
+    prelude: &[ModuleDef],
+    acc: &mut Vec<RequiredImport>,
+) {
+    for node in expanded.descendants() {
+        // Only the first segment of a path has to be in scope
+        let path = match ast::Path::cast(node) {
+            Some(it) if it.qualifier().is_none() => it,
+            _ => continue,
+        };
+        let def = match sema.resolve_path(&path) {
+            Some(PathResolution::Def(ModuleDef::Module(_)))
+            | Some(PathResolution::Def(ModuleDef::BuiltinType(_))) => continue,
+            Some(PathResolution::Def(it)) => it,
+            _ => continue,
+        };
+        if let Some(path) = root.find_use_path(sema.db, def).map(|it| it.to_string()) {
+            if acc.iter().all(|it| it.path != path) {
+                acc.push(RequiredImport { path, from_prelude: prelude.contains(&def) });
+            }
+        }
+    }
+}
+
+/// Names the crates defining the items the expansion of the macro call at
+/// `position` refers to, as the calling crate knows them: `crate` for itself
+/// and the dependency name for the others.
+pub(crate) fn expansion_referenced_crates(
+    db: &RootDatabase,
+    position: FilePosition,
+) -> Result<Vec<String>, ExpandMacroError> {
+    let sema = Semantics::new(db);
+    let (mac, expansion) = match expand_macro_at(&sema, position, &ExpansionLimits::default())? {
+        Some(it) => it,
+        None => return Ok(Vec::new()),
+    };
+    let krate = match sema.scope(mac.syntax()).module() {
+        Some(it) => it.krate(),
+        None => return Ok(Vec::new()),
+    };
+    let deps = krate.dependencies(db);
+
+    let mut crates = Vec::new();
+    for expanded in &expansion.expansions {
+        collect_referenced_crates(&sema, expanded, &mut crates);
+    }
+    let crates = crates
+        .into_iter()
+        .filter_map(|it| {
+            if it == krate {
+                return Some("crate".to_string());
+            }
+            deps.iter().find(|dep| dep.krate == it).map(|dep| dep.name.to_string())
+        })
+        .collect();
+    Ok(crates)
+}
+
+fn collect_referenced_crates(
+    sema: &Semantics<RootDatabase>,
+    expanded: &SyntaxNode,
+    acc: &mut Vec<hir::Crate>,
+) {
+    for node in expanded.descendants() {
+        // Only the item a path ends at is referenced, not the modules on the way
+        let path = match ast::Path::cast(node) {
+            Some(it) if it.syntax().parent().and_then(ast::Path::cast).is_none() => it,
+            _ => continue,
+        };
+        let krate = match sema.resolve_path(&path) {
+            Some(PathResolution::Def(ModuleDef::Module(it))) => it.krate(),
+            Some(PathResolution::Def(it)) => match it.module(sema.db) {
+                Some(module) => module.krate(),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if !acc.contains(&krate) {
+            acc.push(krate);
+        }
+    }
+}
+
+/// Expands `macro_call` and the calls it produces, also returning for each
+/// token of the expanded tree, in order, where in the source it was written.
+fn expand_macro_recur_with_origins(
+    sema: &Semantics<RootDatabase>,
+    macro_call: &ast::MacroCall,
+    budget: &mut Budget,
+    depth: usize,
+) -> Option<(SyntaxNode, Vec<Option<FileRange>>)> {
+    let (expanded, _) = sema.expand_and_lower(macro_call)?;
+    let tokens = expanded.descendants_with_tokens().filter(|it| it.as_token().is_some()).count();
+    if !budget.spend(macro_call, depth, tokens) {
+        return None;
+    }
+    budget.expansions.push(expanded.clone());
+    if depth >= budget.depth {
+        expanded.descendants().filter_map(ast::MacroCall::cast).for_each(|it| budget.skip(&it));
+        let mut origins = Vec::new();
+        collect_origins(sema, expanded.clone().into(), &FxHashMap::default(), &mut origins);
+        return Some((expanded, origins));
+    }
+
+    let children = expanded.descendants().filter_map(ast::MacroCall::cast);
+    let skipped: Vec<SyntaxNode> = expanded
+        .descendants()
+        .filter(|it| is_rustfmt_skip(it) && !it.ancestors().skip(1).any(|it| is_rustfmt_skip(&it)))
+        .collect();
+    let mut replaces: FxHashMap<SyntaxElement, SyntaxElement> = FxHashMap::default();
+    let mut replaced_origins: FxHashMap<SyntaxElement, Vec<Option<FileRange>>> =
+        FxHashMap::default();
+
+    for child in children.into_iter() {
+        if budget.is_out_of_time() {
+            budget.skip(&child);
+            continue;
+        }
+        if let Some((new_node, origins)) =
+            expand_macro_recur_with_origins(sema, &child, budget, depth + 1)
+        {
+            // Replace the whole node if it is root
+            // `replace_descendants` will not replace the parent node
+            // but `SyntaxNode::descendants include itself
+            if expanded == *child.syntax() {
+                return Some((new_node, origins));
+            }
+            replaces.insert(child.syntax().clone().into(), new_node.into());
+            replaced_origins.insert(child.syntax().clone().into(), origins);
+        }
+    }
+
+    for item in skipped {
+        if let Some(new_item) = with_original_layout(sema, &item, &replaces) {
+            // The whitespace of the original layout isn't mapped back
+            let tokens = new_item.descendants_with_tokens().filter(|it| it.as_token().is_some());
+            replaced_origins.insert(item.clone().into(), vec![None; tokens.count()]);
+            replaces.insert(item.into(), new_item.into());
+        }
+    }
+
+    let mut origins = Vec::new();
+    collect_origins(sema, expanded.clone().into(), &replaced_origins, &mut origins);
+    Some((replace_descendants(&expanded, &|n| replaces.get(n).cloned()), origins))
+}
+
+/// Pushes where in the source each token of `element` was written, taking
+/// the origins of the replaced elements from `replaced`.
+fn collect_origins(
+    sema: &Semantics<RootDatabase>,
+    element: SyntaxElement,
+    replaced: &FxHashMap<SyntaxElement, Vec<Option<FileRange>>>,
+    acc: &mut Vec<Option<FileRange>>,
+) {
+    if let Some(origins) = replaced.get(&element) {
+        acc.extend(origins.iter().cloned());
+        return;
+    }
+    match element {
+        NodeOrToken::Token(token) => acc.push(sema.original_token_range(&token)),
+        NodeOrToken::Node(node) => {
+            for child in node.children_with_tokens() {
+                collect_origins(sema, child, replaced, acc);
+            }
+        }
+    }
+}
+
+/// Carries the origins of the tokens of `before` over to the tokens of
+/// `after`, which `before` was rewritten into by inserting and removing
+/// tokens.
+fn realign_origins(
+    before: &SyntaxNode,
+    origins: &[Option<FileRange>],
+    after: &SyntaxNode,
+) -> FxHashMap<SyntaxToken, FileRange> {
+    let tokens = |node: &SyntaxNode| -> Vec<SyntaxToken> {
+        node.descendants_with_tokens().filter_map(|it| it.into_token()).collect()
+    };
+    let same = |a: &SyntaxToken, b: &SyntaxToken| a.kind() == b.kind() && a.text() == b.text();
+    let (before, after) = (tokens(before), tokens(after));
+
+    let mut res = FxHashMap::default();
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() && j < after.len() {
+        if same(&before[i], &after[j]) {
+            if let Some(origin) = origins.get(i).cloned().flatten() {
+                res.insert(after[j].clone(), origin);
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+        // Skip whichever of the inserted or the removed tokens is shorter
+        let inserted = after[j..].iter().position(|it| same(&before[i], it));
+        let removed = before[i..].iter().position(|it| same(it, &after[j]));
+        match (inserted, removed) {
+            (Some(inserted), Some(removed)) if inserted <= removed => j += inserted,
+            (Some(inserted), None) => j += inserted,
+            (_, Some(removed)) => i += removed,
+            (None, None) => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res
+}
+
+fn is_rustfmt_skip(node: &SyntaxNode) -> bool {
+    let item = match ast::ModuleItem::cast(node.clone()) {
+        Some(it) => it,
+        None => return false,
+    };
+    item.attrs().filter_map(|it| it.path()).any(|it| it.syntax().text() == "rustfmt::skip")
+}
+
+/// Re-creates an item marked `#[rustfmt::skip]` with the whitespace and
+/// comments it has in the macro source. Where the source doesn't tell, like
+/// around substituted fragments, the usual spacing is used.
+fn with_original_layout(
+    sema: &Semantics<RootDatabase>,
+    item: &SyntaxNode,
+    replaces: &FxHashMap<SyntaxElement, SyntaxElement>,
+) -> Option<SyntaxNode> {
+    struct Piece {
+        text: String,
+        first: SyntaxKind,
+        last: SyntaxKind,
+        /// The tokens the ends of this piece were produced from
+        origin: (Option<SyntaxToken>, Option<SyntaxToken>),
+    }
+
+    fn collect(
+        sema: &Semantics<RootDatabase>,
+        element: SyntaxElement,
+        replaces: &FxHashMap<SyntaxElement, SyntaxElement>,
+        acc: &mut Vec<Piece>,
+    ) {
+        // Nested calls are spliced in with the layout of their expansion
+        if let (Some(NodeOrToken::Node(node)), NodeOrToken::Node(call)) =
+            (replaces.get(&element), &element)
+        {
+            if let (Some(first), Some(last)) = (node.first_token(), node.last_token()) {
+                let origin = (
+                    call.first_token().and_then(|it| sema.original_token(&it)),
+                    call.last_token().and_then(|it| sema.original_token(&it)),
+                );
+                let text = insert_whitespaces(node.clone(), &|token| token.text().to_string());
+                acc.push(Piece { text, first: first.kind(), last: last.kind(), origin });
+            }
+            return;
+        }
+        match element {
+            NodeOrToken::Node(node) => {
+                node.children_with_tokens().for_each(|it| collect(sema, it, replaces, acc))
+            }
+            NodeOrToken::Token(token) if !token.kind().is_trivia() => {
+                let origin = sema.original_token(&token);
+                acc.push(Piece {
+                    text: token.text().to_string(),
+                    first: token.kind(),
+                    last: token.kind(),
+                    origin: (origin.clone(), origin),
+                })
+            }
+            NodeOrToken::Token(_) => (),
+        }
+    }
+
+    let mut pieces = Vec::new();
+    collect(sema, item.clone().into(), replaces, &mut pieces);
+
+    // Each gap is either taken from the source or made up
+    let policy = SpacingPolicy::default();
+    let gaps: Vec<(String, bool)> = pieces
+        .windows(2)
+        .map(|it| {
+            let original = match (&it[0].origin.1, &it[1].origin.0) {
+                (Some(left), Some(right)) => trivia_between(left, right),
+                _ => None,
+            };
+            match original {
+                Some(gap) => (gap, true),
+                None => match policy.between(it[0].last, it[1].first) {
+                    Spacing::None => (String::new(), false),
+                    Spacing::Space => (" ".to_string(), false),
+                    Spacing::Newline => ("\n".to_string(), false),
+                },
+            }
+        })
+        .collect();
+
+    // The item is usually indented in the macro source, but shouldn't be in
+    // the expansion
+    let mut dedent: Option<usize> = None;
+    for (gap, _) in gaps.iter().filter(|(_, original)| *original) {
+        let mut lines = gap.split('\n').skip(1).peekable();
+        while let Some(line) = lines.next() {
+            // Blank lines don't count, but the last one is followed by a token
+            if lines.peek().is_some() && line.trim().is_empty() {
+                continue;
+            }
+            let indent = line.len() - line.trim_start().len();
+            dedent = Some(dedent.map_or(indent, |it| it.min(indent)));
+        }
+    }
+    let dedent = dedent.unwrap_or(0);
+    let mut text = String::new();
+    for (idx, piece) in pieces.iter().enumerate() {
+        text += &piece.text;
+        if let Some((gap, original)) = gaps.get(idx) {
+            let mut lines = gap.split('\n');
+            text += lines.next().unwrap_or_default();
+            for line in lines {
+                text.push('\n');
+                match line.get(dedent..) {
+                    Some(line) if *original => text += line,
+                    _ => text += line.trim_start(),
+                }
+            }
+        }
+    }
+
+    let file = ast::SourceFile::parse(&text).tree();
+    let mut items = file.items();
+    match (items.next(), items.next()) {
+        (Some(it), None) if it.syntax().kind() == item.kind() => Some(it.syntax().clone()),
+        _ => None,
+    }
+}
+
+/// The whitespace and comments between two tokens, if nothing else separates
+/// them.
+fn trivia_between(left: &SyntaxToken, right: &SyntaxToken) -> Option<String> {
+    let mut res = String::new();
+    let mut token = left.next_token()?;
+    while token != *right {
+        if !token.kind().is_trivia() {
+            return None;
+        }
+        res += token.text();
+        token = token.next_token()?;
+    }
+    Some(res)
+}
+
+fn qualified_name(macro_call: &ast::MacroCall) -> Option<String> {
+    let path = macro_call.path()?;
+    let mut res: String = path
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| !it.kind().is_trivia())
+        .map(|it| it.text().to_string())
+        .collect();
+    res.push('!');
+    Some(res)
+}
+
+fn introduced_names(expanded: &SyntaxNode) -> Vec<String> {
+    top_level_items(expanded).iter().filter_map(item_name).collect()
+}
+
+fn top_level_items(expanded: &SyntaxNode) -> Vec<SyntaxNode> {
+    let mut res = Vec::new();
+    for node in expanded.children() {
+        // Nested calls have already been replaced by their own expansion
+        if node.kind() == SyntaxKind::MACRO_ITEMS {
+            res.extend(top_level_items(&node));
+        } else if ast::ModuleItem::can_cast(node.kind()) {
+            res.push(node);
+        }
+    }
+    res
+}
+
+fn item_name(item: &SyntaxNode) -> Option<String> {
+    let name = match ast::ModuleItem::cast(item.clone())? {
+        ast::ModuleItem::StructDef(it) => it.name(),
+        ast::ModuleItem::UnionDef(it) => it.name(),
+        ast::ModuleItem::EnumDef(it) => it.name(),
+        ast::ModuleItem::FnDef(it) => it.name(),
+        ast::ModuleItem::TraitDef(it) => it.name(),
+        ast::ModuleItem::TypeAliasDef(it) => it.name(),
+        ast::ModuleItem::ConstDef(it) => it.name(),
+        ast::ModuleItem::StaticDef(it) => it.name(),
+        ast::ModuleItem::Module(it) => it.name(),
+        _ => None,
+    };
+    Some(name?.text().to_string())
+}
+
+/// Replaces the bodies of the functions nested more than `max_depth` deep,
+/// counting the function itself.
+fn collapse_fn_bodies(node: SyntaxNode, max_depth: usize) -> SyntaxNode {
+    let placeholder = ast::SourceFile::parse("fn f() { /* ... */ }")
+        .tree()
+        .syntax()
+        .descendants()
+        .find_map(ast::BlockExpr::cast)
+        .unwrap();
+    let bodies: FxHashMap<SyntaxElement, SyntaxElement> = node
+        .descendants()
+        .filter_map(ast::FnDef::cast)
+        .filter(|it| {
+            let depth = it.syntax().ancestors().filter(|it| it.kind() == SyntaxKind::FN_DEF);
+            depth.count() > max_depth
+        })
+        .filter_map(|it| it.body())
+        .map(|it| (it.syntax().clone().into(), placeholder.syntax().clone().into()))
+        .collect();
+
+    replace_descendants(&node, &|n| bodies.get(n).cloned())
+}
+
+/// Removes the nodes whose `#[cfg]` attributes are disabled with `options`,
+/// together with the comma following them in lists.
+fn strip_cfg_disabled(mut node: SyntaxNode, options: &CfgOptions) -> SyntaxNode {
+    while let Some(disabled) = node.descendants().skip(1).find(|it| is_cfg_disabled(it, options)) {
+        let parent = match disabled.parent() {
+            Some(it) => it,
+            None => break,
+        };
+        let last = match non_trivia_sibling(disabled.clone().into(), Direction::Next) {
+            Some(it) if it.kind() == T![,] => it,
+            _ => disabled.clone().into(),
+        };
+        let parent = replace_children(&parent, disabled.into()..=last, &mut iter::empty());
+        node = parent.ancestors().last().unwrap_or(parent);
+    }
+    node
+}
+
+fn is_cfg_disabled(node: &SyntaxNode, options: &CfgOptions) -> bool {
+    node.children()
+        .filter_map(ast::Attr::cast)
+        .filter(|it| it.simple_name().as_ref().map(|it| it.as_str()) == Some("cfg"))
+        .filter_map(|it| match it.input()? {
+            ast::AttrInput::TokenTree(it) => ra_mbe::ast_to_token_tree(&it),
+            ast::AttrInput::Literal(_) => None,
+        })
+        .any(|(tt, _)| options.is_cfg_enabled(&tt) == Some(false))
+}
+
+/// Puts parentheses around binary expressions in positions that bind tighter,
+/// like `!$cond` with `$cond` being `n > 0`. Such trees only come from `expr`
+/// fragments, which keep their grouping without the parentheses to show it.
+fn parenthesize_operands(node: SyntaxNode) -> SyntaxNode {
+    use SyntaxKind::*;
+
+    let operands: FxHashMap<SyntaxElement, SyntaxElement> =
+        node.descendants()
+            .filter(|it| it.kind() == BIN_EXPR || it.kind() == RANGE_EXPR)
+            .filter(|it| {
+                let parent = match it.parent() {
+                    Some(it) => it,
+                    None => return false,
+                };
+                match parent.kind() {
+                    PREFIX_EXPR | REF_EXPR => true,
+                    METHOD_CALL_EXPR | FIELD_EXPR | AWAIT_EXPR | TRY_EXPR | CAST_EXPR
+                    | INDEX_EXPR => parent.first_child().as_ref() == Some(it),
+                    _ => false,
+                }
+            })
+            .map(|it| {
+                let parenthesized = make::expr_from_text(&format!("({})", it));
+                (it.into(), parenthesized.syntax().clone().into())
+            })
+            .collect();
+
+    replace_descendants(&node, &|n| operands.get(n).cloned())
+}
+
+fn collapse_redundant_blocks(node: SyntaxNode) -> SyntaxNode {
+    let runs: FxHashMap<SyntaxElement, SyntaxElement> = node
+        .descendants()
+        .filter_map(ast::BlockExpr::cast)
+        .filter(|it| {
+            let outer =
+                it.syntax().parent().and_then(|it| it.parent()).and_then(ast::BlockExpr::cast);
+            outer.and_then(|it| only_block(&it)).as_ref() != Some(it)
+        })
+        .filter_map(|outermost| {
+            let mut innermost = only_block(&outermost)?;
+            let mut removed = 1;
+            while let Some(it) = only_block(&innermost) {
+                innermost = it;
+                removed += 1;
+            }
+            let collapsed = collapse_redundant_blocks(innermost.syntax().clone());
+            Some((outermost.syntax().clone().into(), with_note(&collapsed, removed)?.into()))
+        })
+        .collect();
+
+    replace_descendants(&node, &|n| runs.get(n).cloned())
+}
+
+/// The block in `block` if there is nothing else in it.
+fn only_block(block: &ast::BlockExpr) -> Option<ast::BlockExpr> {
+    let is_plain =
+        |it: &ast::BlockExpr| it.syntax().first_token().map(|it| it.kind()) == Some(T!['{']);
+    let inner = block.block()?;
+    if !is_plain(block) || inner.statements().next().is_some() {
+        return None;
+    }
+    match inner.expr()? {
+        ast::Expr::BlockExpr(it) if is_plain(&it) => Some(it),
+        _ => None,
+    }
+}
+
+/// Puts a `/* N nested blocks collapsed */` comment at the start of `block`.
+fn with_note(block: &SyntaxNode, removed: usize) -> Option<SyntaxNode> {
+    let plural = if removed == 1 { "" } else { "s" };
+    let note = format!("{{ /* {} nested block{} collapsed */ }}", removed, plural);
+    let comment = ast::SourceFile::parse(&note)
+        .tree()
+        .syntax()
+        .descendants_with_tokens()
+        .find(|it| it.kind() == SyntaxKind::COMMENT)?;
+    let l_curly = block.first_token()?;
+    let inner = l_curly.parent();
+    let new_inner =
+        insert_children(&inner, InsertPosition::After(l_curly.into()), &mut iter::once(comment));
+    let (inner, new_inner): (SyntaxElement, SyntaxElement) = (inner.into(), new_inner.into());
+    Some(replace_descendants(block, &|n| if *n == inner { Some(new_inner.clone()) } else { None }))
+}
+
+/// Whitespace between two adjacent tokens of the rendered expansion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Spacing {
+    None,
+    Space,
+    /// A line break followed by the current indentation.
+    Newline,
+}
+
+struct SpacingRule {
+    left: fn(SyntaxKind) -> bool,
+    right: fn(SyntaxKind) -> bool,
+    spacing: Spacing,
+}
+
+/// Decides the spacing between two adjacent tokens from their kinds alone.
+/// Rules are tried in order, the first one accepting both kinds wins.
+struct SpacingPolicy {
+    rules: Vec<SpacingRule>,
+}
+
+impl SpacingPolicy {
+    fn new() -> SpacingPolicy {
+        SpacingPolicy { rules: Vec::new() }
+    }
+
+    fn rule(
+        mut self,
+        left: fn(SyntaxKind) -> bool,
+        right: fn(SyntaxKind) -> bool,
+        spacing: Spacing,
+    ) -> SpacingPolicy {
+        self.rules.push(SpacingRule { left, right, spacing });
+        self
+    }
+
+    fn between(&self, left: SyntaxKind, right: SyntaxKind) -> Spacing {
+        self.rules
+            .iter()
+            .find(|rule| (rule.left)(left) && (rule.right)(right))
+            .map_or(Spacing::None, |rule| rule.spacing)
+    }
+}
+
+impl Default for SpacingPolicy {
+    fn default() -> SpacingPolicy {
+        use SyntaxKind::*;
+
+        SpacingPolicy::new()
+            // Blocks
+            .rule(|it| it == L_CURLY, |it| it == R_CURLY, Spacing::None)
+            // `{ /* ... */ }` left by `collapse_fn_bodies`
+            .rule(|it| it == L_CURLY, |it| it == COMMENT, Spacing::Space)
+            .rule(|it| it == COMMENT, |it| it == R_CURLY, Spacing::Space)
+            // The note left by `collapse_redundant_blocks`
+            .rule(|it| it == COMMENT, |_| true, Spacing::Newline)
+            .rule(|it| it == L_CURLY, |_| true, Spacing::Newline)
+            .rule(|_| true, |it| it == R_CURLY, Spacing::Newline)
+            .rule(|it| it == R_CURLY, |it| is_closing(it) || is_separator(it), Spacing::None)
+            .rule(|it| it == R_CURLY, |_| true, Spacing::Newline)
+            .rule(|it| is_word(it) || is_closing(it), |it| it == L_CURLY, Spacing::Space)
+            // Closure bodies, `|x| {` and `|| {`
+            .rule(|it| it == T![|] || it == T![||], |it| it == L_CURLY, Spacing::Space)
+            .rule(
+                |it| it == T![move] || it == T![async],
+                |it| it == T![|] || it == T![||],
+                Spacing::Space,
+            )
+            // Separators
+            .rule(|it| it == T![;], |_| true, Spacing::Newline)
+            .rule(|it| it == T![,], is_closing, Spacing::None)
+            .rule(|it| it == T![,], |_| true, Spacing::Space)
+            .rule(|it| it == T![:], |_| true, Spacing::Space)
+            // Paths stay tight, apart from leading `::` after a keyword, like
+            // `return ::std::...`
+            .rule(|it| it.is_keyword() && !is_path_keyword(it), |it| it == T![::], Spacing::Space)
+            .rule(|it| it == T![::], |_| true, Spacing::None)
+            .rule(|_| true, |it| it == T![::], Spacing::None)
+            // Operators
+            .rule(|_| true, is_spaced_op, Spacing::Space)
+            .rule(is_spaced_op, |_| true, Spacing::Space)
+            // Words
+            .rule(|it| it.is_keyword(), |it| it == T![!], Spacing::Space)
+            .rule(
+                |it| it.is_keyword() && it != T![fn] && it != T![pub] && !is_path_keyword(it),
+                |it| it == T!['('] || it == T!['['],
+                Spacing::Space,
+            )
+            .rule(
+                |it| it.is_keyword() && it != T![dyn] && !is_path_keyword(it),
+                |it| it == T![&] || it == T![*],
+                Spacing::Space,
+            )
+            .rule(is_text, |it| !it.is_punct(), Spacing::Space)
+            .rule(is_word, is_word, Spacing::Space)
+            .rule(|it| it == T![')'] || it == T![']'], is_word, Spacing::Space)
+    }
+}
+
+// FIXME: It would also be cool to share logic here and in the mbe tests,
+// which are pretty unreadable at the moment.
+fn insert_whitespaces(syn: SyntaxNode, paint: &dyn Fn(&SyntaxToken) -> String) -> String {
+    insert_whitespaces_with_ranges(syn, paint, None, true).0
+}
+
+/// Like `insert_whitespaces`, also returning where each token ends up in the
+/// output. Match arms whose patterns don't fit in `max_width` get one pattern
+/// per line, and `use` groups one import per line. Without `keep_layout`,
+/// `#[rustfmt::skip]` items are laid out like the others.
+fn insert_whitespaces_with_ranges(
+    syn: SyntaxNode,
+    paint: &dyn Fn(&SyntaxToken) -> String,
+    max_width: Option<usize>,
+    keep_layout: bool,
+) -> (String, FxHashMap<SyntaxToken, TextRange>) {
+    use SyntaxKind::*;
+
+    let policy = SpacingPolicy::default();
+    let tokens: Vec<SyntaxToken> = syn
+        .preorder_with_tokens()
+        .filter_map(|event| {
+            if let WalkEvent::Enter(NodeOrToken::Token(token)) = event {
+                Some(token)
+            } else {
+                None
+            }
+        })
+        // mbe may insert whitespace between puncts, but we lay out tokens
+        // ourselves, except in items that keep their original layout
+        .filter(|token| match token.kind() {
+            WHITESPACE => keep_layout && skipped_item(token).is_some(),
+            COMMENT => keep_layout || skipped_item(token).is_none(),
+            _ => true,
+        })
+        .collect();
+
+    let mut res = String::new();
+    let mut ranges = FxHashMap::default();
+    let mut indent = 0usize;
+    let mut column = 0usize;
+    let mut wrapped_pats = Vec::new();
+    let mut wrapped_use_lists = Vec::new();
+
+    for (idx, token) in tokens.iter().enumerate() {
+        if let Some(max_width) = max_width {
+            if let Some(pat) = arm_or_pat_starting_at(token) {
+                if column + or_pat_width(&pat) > max_width {
+                    wrapped_pats.push(pat);
+                }
+            }
+            if token.kind() == L_CURLY && token.parent().kind() == USE_TREE_LIST {
+                if column + use_tree_list_width(&token.parent()) > max_width {
+                    wrapped_use_lists.push(token.parent());
+                }
+            }
+        }
+        let start = TextUnit::of_str(&res);
+        res += &paint(token);
+        column = match token.text().rfind('\n') {
+            Some(it) => token.text().len() - it - 1,
+            None => column + token.text().len(),
+        };
+        ranges.insert(token.clone(), TextRange::from_to(start, TextUnit::of_str(&res)));
+        if token.kind() == L_CURLY {
+            indent += 1;
+        }
+
+        let next = match tokens.get(idx + 1) {
+            Some(it) => it,
+            None => break,
+        };
+        if next.kind() == R_CURLY {
+            indent = indent.saturating_sub(1);
+        }
+
+        let continues_pat = next.kind() == T![|] && wrapped_pats.contains(&next.parent());
+        let spacing = match token.kind() {
+            _ if continues_pat => Spacing::Newline,
+            _ if keep_layout
+                && skipped_item(token).is_some()
+                && skipped_item(token) == skipped_item(next) =>
+            {
+                Spacing::None
+            }
+            L_CURLY if is_inline_const_block(token) => Spacing::Space,
+            // Imports that don't fit in `max_width` get one per line
+            L_CURLY | T![,] if wrapped_use_lists.contains(&token.parent()) => Spacing::Newline,
+            _ if next.kind() == R_CURLY && wrapped_use_lists.contains(&next.parent()) => {
+                Spacing::Newline
+            }
+            // Imports stay on one line, like `use std::{fmt, io};`
+            L_CURLY if token.parent().kind() == USE_TREE_LIST => Spacing::None,
+            _ if next.kind() == R_CURLY && next.parent().kind() == USE_TREE_LIST => Spacing::None,
+            // Struct patterns stay on one line, like `Foo { x, y: 2, .. }`
+            L_CURLY if token.parent().kind() == RECORD_FIELD_PAT_LIST && next.kind() != R_CURLY => {
+                Spacing::Space
+            }
+            _ if next.kind() == R_CURLY
+                && next.parent().kind() == RECORD_FIELD_PAT_LIST
+                && token.kind() != L_CURLY =>
+            {
+                Spacing::Space
+            }
+            // Struct pattern parameters, like `Foo { x }: Foo`
+            R_CURLY if token.parent().kind() == RECORD_FIELD_PAT_LIST && next.kind() == T![:] => {
+                Spacing::None
+            }
+            R_CURLY
+                if token.parent().kind() == RECORD_FIELD_PAT_LIST
+                    && !is_closing(next.kind())
+                    && !is_separator(next.kind()) =>
+            {
+                Spacing::Space
+            }
+            _ if next.kind() == R_CURLY && is_inline_const_block(next) => Spacing::Space,
+            R_BRACK if is_item_attr(token) => Spacing::Newline,
+            // Field attributes go on their own line, like `#[cfg(..)]` in structs
+            R_BRACK
+                if token.parent().kind() == ATTR
+                    && token.parent().parent().map(|it| it.kind()) == Some(RECORD_FIELD_DEF) =>
+            {
+                Spacing::Newline
+            }
+            R_BRACK if token.parent().kind() == ATTR => Spacing::Space,
+            T![;] if !is_terminator(token) => Spacing::Space,
+            // Token trees split `::` into two `:`, like in `#[allow(clippy::all)]`
+            T![:]
+                if token.parent().kind() == TOKEN_TREE
+                    && (next.kind() == T![:] || idx > 0 && tokens[idx - 1].kind() == T![:]) =>
+            {
+                Spacing::None
+            }
+            // Generated macro definitions, like `macro_rules! foo { ($v:vis $e:expr) => {} }`
+            T![!] if token.parent().kind() == MACRO_CALL && next.parent().kind() == NAME => {
+                Spacing::Space
+            }
+            // Negation stays tight against its operand, and the `!` of a call
+            // left unexpanded against the delimiter, like `!foo!(x)`
+            T![!]
+                if token.parent().kind() == PREFIX_EXPR || token.parent().kind() == MACRO_CALL =>
+            {
+                Spacing::None
+            }
+            T![:]
+                if token.parent().kind() == TOKEN_TREE
+                    && idx > 1
+                    && tokens[idx - 1].kind() == IDENT
+                    && tokens[idx - 2].kind() == T![$] =>
+            {
+                Spacing::None
+            }
+            k if next.kind() == T![$]
+                && next.parent().kind() == TOKEN_TREE
+                && (is_word(k) || is_closing(k)) =>
+            {
+                Spacing::Space
+            }
+            T![=] if token.parent().kind() == TOKEN_TREE && next.kind() == T![>] => Spacing::None,
+            T![>]
+                if token.parent().kind() == TOKEN_TREE
+                    && idx > 0
+                    && tokens[idx - 1].kind() == T![=] =>
+            {
+                Spacing::Space
+            }
+            // The never type, like in `fn f() -> ! {`
+            T![!]
+                if token.parent().kind() == NEVER_TYPE
+                    && !is_closing(next.kind())
+                    && !is_separator(next.kind()) =>
+            {
+                Spacing::Space
+            }
+            // The end of generics, like in `impl<T> Foo<T> where`
+            T![>] if is_generics_end(token) && is_word(next.kind()) => Spacing::Space,
+            // Pointer and reference types, like `*mut *const T` and `&mut [u8]`
+            T![mut] | T![const]
+                if token.parent().kind() == POINTER_TYPE
+                    || token.parent().kind() == REFERENCE_TYPE =>
+            {
+                Spacing::Space
+            }
+            // Closure bodies, like `|x| x + 1`
+            T![|] | T![||] if is_closure_params_end(token) => Spacing::Space,
+            // Binary operators whose tokens are also used elsewhere, like `<` in
+            // generics or `&&` in `&&x`
+            k if is_overloaded_op(k) && token.parent().kind() == BIN_EXPR => Spacing::Space,
+            _ if is_overloaded_op(next.kind()) && next.parent().kind() == BIN_EXPR => {
+                Spacing::Space
+            }
+            // Or-patterns, like `A | B`
+            T![|] if token.parent().kind() == OR_PAT => Spacing::Space,
+            _ if next.kind() == T![|] && next.parent().kind() == OR_PAT => Spacing::Space,
+            // One match arm, struct field or enum variant per line
+            T![,]
+                if token.parent().kind() == MATCH_ARM_LIST
+                    || token.parent().kind() == RECORD_FIELD_DEF_LIST
+                    || token.parent().kind() == ENUM_VARIANT_LIST =>
+            {
+                Spacing::Newline
+            }
+            // The parser doesn't know `yield` yet, so it is lexed as an identifier
+            IDENT
+                if token.text() == "yield"
+                    && !is_closing(next.kind())
+                    && !is_separator(next.kind())
+                    && next.kind() != R_CURLY =>
+            {
+                Spacing::Space
+            }
+            // Syntax the parser doesn't know yet (e.g. the `*` in `dyn* Trait`)
+            _ if token.parent().kind() == ERROR && is_text(next.kind()) => Spacing::Space,
+            // An empty `{}` sticks to what precedes it
+            k if (is_word(k) || is_closing(k))
+                && next.kind() == L_CURLY
+                && tokens.get(idx + 2).map(|it| it.kind()) == Some(R_CURLY) =>
+            {
+                Spacing::None
+            }
+            _ => policy.between(token.kind(), next.kind()),
+        };
+
+        match spacing {
+            Spacing::None => (),
+            Spacing::Space => {
+                res.push(' ');
+                column += 1;
+            }
+            Spacing::Newline => {
+                let indent = if continues_pat { indent + 1 } else { indent };
+                res.push('\n');
+                res += &"  ".repeat(indent);
+                column = 2 * indent;
+            }
+        }
+    }
+
+    (res, ranges)
+}
+
+/// The or-pattern of a match arm, like `A | B` in `A | B => 0`, if `token` is
+/// its first token.
+fn arm_or_pat_starting_at(token: &SyntaxToken) -> Option<SyntaxNode> {
+    let pat = token.parent().ancestors().find(|it| it.kind() == SyntaxKind::OR_PAT)?;
+    if pat.parent()?.kind() != SyntaxKind::MATCH_ARM || pat.first_token()? != *token {
+        return None;
+    }
+    Some(pat)
+}
+
+/// How wide `list` is rendered on one line, together with the following `;`
+/// or `,`.
+fn use_tree_list_width(list: &SyntaxNode) -> usize {
+    let tokens = list.descendants_with_tokens().filter_map(|it| it.into_token());
+    let width: usize = tokens
+        .map(|it| match it.kind() {
+            T![,] => 2,
+            SyntaxKind::WHITESPACE => 0,
+            _ => it.text().len(),
+        })
+        .sum();
+    width + 1
+}
+
+/// How wide `pat` is rendered on one line, together with the following `=>`.
+fn or_pat_width(pat: &SyntaxNode) -> usize {
+    let tokens = pat.descendants_with_tokens().filter_map(|it| it.into_token());
+    let width: usize = tokens
+        .map(|it| match it.kind() {
+            T![|] => 3,
+            SyntaxKind::WHITESPACE => 0,
+            _ => it.text().len(),
+        })
+        .sum();
+    width + " =>".len()
+}
+
+fn is_text(k: SyntaxKind) -> bool {
+    k.is_keyword() || k.is_literal() || k == SyntaxKind::IDENT
+}
+
+fn is_word(k: SyntaxKind) -> bool {
+    is_text(k) || k == SyntaxKind::LIFETIME || k == T![_]
+}
+
+/// Keywords that can be a segment of a path, as in `self::foo`.
+fn is_path_keyword(k: SyntaxKind) -> bool {
+    k == T![self] || k == T![super] || k == T![crate]
+}
+
+fn is_closing(k: SyntaxKind) -> bool {
+    k == T![')'] || k == T![']'] || k == T![>]
+}
+
+fn is_separator(k: SyntaxKind) -> bool {
+    k == T![,] || k == T![;] || k == T![.] || k == T![?]
+}
+
+/// Whether `token` is the last `|` of a closure's parameters.
+fn is_closure_params_end(token: &SyntaxToken) -> bool {
+    let params = token.parent();
+    params.kind() == SyntaxKind::PARAM_LIST
+        && params.parent().map(|it| it.kind()) == Some(SyntaxKind::LAMBDA_EXPR)
+        && params.last_token().as_ref() == Some(token)
+}
+
+fn is_overloaded_op(k: SyntaxKind) -> bool {
+    k == T![<] || k == T![>] || k == T![&&] || k == T![||]
+}
+
+/// Operators that are never unary, so they can always be spaced out.
+fn is_spaced_op(k: SyntaxKind) -> bool {
+    use SyntaxKind::*;
+
+    match k {
+        EQ | FAT_ARROW | THIN_ARROW | PLUS | SLASH | PERCENT | EQEQ | NEQ | LTEQ | GTEQ
+        | PLUSEQ | MINUSEQ | STAREQ | SLASHEQ | PERCENTEQ | AMPEQ | PIPEEQ | CARETEQ | SHLEQ
+        | SHREQ => true,
+        _ => false,
+    }
+}
+
+/// Whether a `;` ends a statement or an item. The ones in `[u8; N]`, `[0; N]`
+/// or between the parentheses of an unexpanded macro call don't.
+fn is_terminator(semi: &SyntaxToken) -> bool {
+    let parent = semi.parent();
+    match parent.kind() {
+        SyntaxKind::ARRAY_TYPE | SyntaxKind::ARRAY_EXPR => false,
+        SyntaxKind::TOKEN_TREE => parent.first_token().map_or(true, |it| it.kind() == T!['{']),
+        _ => true,
+    }
+}
+
+fn is_generics_end(token: &SyntaxToken) -> bool {
+    let parent = token.parent().kind();
+    parent == SyntaxKind::TYPE_ARG_LIST || parent == SyntaxKind::TYPE_PARAM_LIST
+}
+
+/// The outermost `#[rustfmt::skip]` item containing the token.
+fn skipped_item(token: &SyntaxToken) -> Option<SyntaxNode> {
+    token.parent().ancestors().filter(is_rustfmt_skip).last()
+}
+
+/// A `const { N }` block without statements stays on one line, like it would
+/// in an array length.
+fn is_inline_const_block(curly: &SyntaxToken) -> bool {
+    let block = match ast::Block::cast(curly.parent()) {
+        Some(it) => it,
+        None => return false,
+    };
+    let is_const = block
+        .syntax()
+        .parent()
+        .and_then(ast::BlockExpr::cast)
+        .and_then(|it| it.syntax().first_token())
+        .map_or(false, |it| it.kind() == T![const]);
+    is_const
+        && block.expr().is_some()
+        && block.statements().next().is_none()
+        && block.items().next().is_none()
+}
+
+fn is_item_attr(token: &SyntaxToken) -> bool {
+    let attr = token.parent();
+    let item = match attr.parent() {
+        Some(it) if attr.kind() == SyntaxKind::ATTR => it.kind(),
+        _ => return false,
+    };
+    ast::ModuleItem::can_cast(item)
+        || item == SyntaxKind::EXTERN_BLOCK
+        || item == SyntaxKind::MACRO_CALL
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+        sync::Arc,
+    };
+
+    use hir::db::AstDatabase;
+    use insta::assert_snapshot;
+
+    use crate::{
+        mock_analysis::{analysis_and_position, MockAnalysis},
+        AnalysisChange,
+    };
+
+    use super::*;
+
+    fn check_expand_macro(fixture: &str) -> ExpandedMacro {
+        check_expand_macro_with_config(fixture, &ExpandMacroConfig::default())
+    }
+
+    fn check_expand_macro_with_config(fixture: &str, config: &ExpandMacroConfig) -> ExpandedMacro {
+        let (analysis, pos) = analysis_and_position(fixture);
+        analysis.expand_macro(pos, config).unwrap().unwrap().unwrap()
+    }
+
+    #[test]
+    fn macro_expand_recursive_expansion() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            () => { fn  b() {} }
+        }
+        macro_rules! foo {
+            () => { bar!(); }
+        }
+        macro_rules! baz {
+            () => { foo!(); }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn b(){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_multiple_lines() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn some_thing() -> u32 {
+                    let a = 0;
+                    a + 10
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn some_thing() -> u32 {
+  let a = 0;
+  a + 10
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_match_ast() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! match_ast {
+            (match $node:ident { $($tt:tt)* }) => { match_ast!(match ($node) { $($tt)* }) };
+
+            (match ($node:expr) {
+                $( ast::$ast:ident($it:ident) => $res:block, )*
+                _ => $catch_all:expr $(,)?
+            }) => {{
+                $( if let Some($it) = ast::$ast::cast($node.clone()) $res else )*
+                { $catch_all }
+            }};
+        }
+
+        fn main() {
+            mat<|>ch_ast! {
+                match container {
+                    ast::TraitDef(it) => {},
+                    ast::ImplBlock(it) => {},
+                    _ => { continue },
+                }
+            }
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "match_ast");
+        assert_snapshot!(res.expansion, @r###"
+{
+  if let Some(it) = ast::TraitDef::cast(container.clone()){}
+  else if let Some(it) = ast::ImplBlock::cast(container.clone()){}
+  else {
+    {
+      continue
+    }
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn expand_and_lower_matches_expand() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! match_ast {
+            (match $node:ident { $($tt:tt)* }) => { match_ast!(match ($node) { $($tt)* }) };
+
+            (match ($node:expr) {
+                $( ast::$ast:ident($it:ident) => $res:block, )*
+                _ => $catch_all:expr $(,)?
+            }) => {{
+                $( if let Some($it) = ast::$ast::cast($node.clone()) $res else )*
+                { $catch_all }
+            }};
+        }
+
+        fn main() {
+            mat<|>ch_ast! {
+                match container {
+                    ast::TraitDef(it) => {},
+                    ast::ImplBlock(it) => {},
+                    _ => { continue },
+                }
+            }
+        }
+        "#,
+        );
+
+        let db: &RootDatabase = &analysis.db;
+        let sema = Semantics::new(db);
+        let file = sema.parse(pos.file_id);
+        let mac = find_macro_call(&sema, &file, pos).unwrap();
+        let (expanded, file_id) = sema.expand_and_lower(&mac).unwrap();
+        assert_eq!(expanded.to_string(), sema.expand(&mac).unwrap().to_string());
+        assert_eq!(db.parse_or_expand(file_id).unwrap().to_string(), expanded.to_string());
+    }
+
+    #[test]
+    fn macro_expand_match_ast_inside_let_statement() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! match_ast {
+            (match $node:ident { $($tt:tt)* }) => { match_ast!(match ($node) { $($tt)* }) };
+            (match ($node:expr) {}) => {{}};
+        }
+
+        fn main() {
+            let p = f(|it| {
+                let res = mat<|>ch_ast! { match c {}};
+                Some(res)
+            })?;
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "match_ast");
+        assert_snapshot!(res.expansion, @r###"{}"###);
+    }
+
+    #[test]
+    fn macro_expand_inner_macro_fail_to_expand() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            (BAD) => {};
+        }
+        macro_rules! foo {
+            () => {bar!()};
+        }
+
+        fn main() {
+            let res = fo<|>o!();
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"bar!()"###);
+    }
+
+    #[test]
+    fn macro_expand_with_dollar_crate() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        #[macro_export]
+        macro_rules! bar {
+            () => {0};
+        }
+        macro_rules! foo {
+            () => {$crate::bar!()};
+        }
+
+        fn main() {
+            let res = fo<|>o!();
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"0"###);
+    }
+
+    #[test]
+    fn macro_expand_const_fn_with_const_generics() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                const fn bar<const N: usize>() -> [u8; N] {
+                    [0; N]
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+const fn bar<const N: usize>() -> [u8; N] {
+  [0; N]
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_with_rustfmt() {
+        fn rustfmt(text: &str) -> Option<String> {
+            let mut rustfmt = Command::new("rustfmt")
+                .args(&["--edition", "2018"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            rustfmt.stdin.as_mut()?.write_all(text.as_bytes()).ok()?;
+            let output = rustfmt.wait_with_output().ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            String::from_utf8(output.stdout).ok()
+        }
+
+        if rustfmt("").is_none() {
+            // rustfmt is not available
+            return;
+        }
+
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn some_thing() -> u32 {
+                    let a = 0;
+                    a + 10
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+            &ExpandMacroConfig {
+                formatter: Some(Arc::new(rustfmt)),
+                ..ExpandMacroConfig::default()
+            },
+        );
+
+        assert_eq!(res.expansion, "fn some_thing() -> u32 {\n    let a = 0;\n    a + 10\n}\n");
+    }
+
+    #[test]
+    fn macro_expand_formatter_failure_falls_back() {
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn some_thing() -> u32 {
+                    let a = 0;
+                    a + 10
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+            &ExpandMacroConfig {
+                formatter: Some(Arc::new(|_: &str| None)),
+                ..ExpandMacroConfig::default()
+            },
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+fn some_thing() -> u32 {
+  let a = 0;
+  a + 10
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_same_name_in_different_modules() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        mod a {
+            macro_rules! foo {
+                () => { fn from_a() {} }
+            }
+            macro_rules! bar {
+                () => { foo!(); }
+            }
+            b<|>ar!();
+        }
+        mod b {
+            macro_rules! foo {
+                () => { fn from_b() {} }
+            }
+            macro_rules! bar {
+                () => { foo!(); }
+            }
+            bar!();
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "bar");
+        assert_snapshot!(res.expansion, @r###"fn from_a(){}"###);
+
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        mod a {
+            macro_rules! foo {
+                () => { fn from_a() {} }
+            }
+            macro_rules! bar {
+                () => { foo!(); }
+            }
+            bar!();
+        }
+        mod b {
+            macro_rules! foo {
+                () => { fn from_b() {} }
+            }
+            macro_rules! bar {
+                () => { foo!(); }
+            }
+            b<|>ar!();
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "bar");
+        assert_snapshot!(res.expansion, @r###"fn from_b(){}"###);
+    }
+
+    #[test]
+    fn macro_expand_impl_trait_in_argument_position() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn show(x: impl Display) {}
+                fn call<T>(f: impl Fn() -> T) -> T { f() }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn show(x: impl Display){}
+fn call<T>(f: impl Fn() -> T) -> T {
+  f()
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_matched_rule_range() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            (a) => { fn a() {} };
+            (b) => { fn b() {} };
+        }
+        f<|>oo!(b);
+        "#,
+        );
+        let res =
+            analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap().unwrap();
+
+        let range = res.matched_rule_range.unwrap();
+        assert_eq!(range.file_id, pos.file_id);
+        let text = analysis.file_text(pos.file_id).unwrap();
+        assert_eq!(&text[range.range], "(b) => { fn b() {} }");
+    }
+
+    #[test]
+    fn macro_expand_trait_method_with_self_bound() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                trait Foo {
+                    fn new() -> Self where Self: Sized;
+                    fn by_ref(&self) -> &Self where Self: Sized { self }
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+trait Foo {
+  fn new() -> Self where Self: Sized;
+  fn by_ref(&self) -> &Self where Self: Sized {
+    self
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_key_value_attributes() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                #[path = "foo.rs"]
+                mod foo;
+                #[link(name = "z")]
+                extern "C" {}
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[path = "foo.rs"]
+mod foo;
+#[link(name = "z")]
+extern "C"{}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_generic_defaults() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                struct Foo<T = u32>(T);
+                struct Bar<K, V = Vec<K>> { k: K, v: V }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+struct Foo<T = u32>(T);
+struct Bar<K, V = Vec<K>> {
+  k: K,
+  v: V
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_unknown_keyword_sequence() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo { () => {
+            auto trait Send {}
+            fn f(x: &'a mut dyn* Trait) -> &'static str {}
+        } }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+auto trait Send{}
+fn f(x: &'a mut dyn* Trait) -> &'static str{}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_include_with_nested_macro() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        #[rustc_builtin_macro]
+        macro_rules! include {() => {}}
+
+        macro_rules! foo {
+            () => { fn generated() {} }
+        }
+
+        inc<|>lude!("generated.rs");
+
+        //- /generated.rs
+        struct Generated;
+        foo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "include");
+        assert_snapshot!(res.expansion, @r###"
+struct Generated;
+fn generated(){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_include_missing_file() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        #[rustc_builtin_macro]
+        macro_rules! include {() => {}}
+
+        inc<|>lude!("missing.rs");
+        "#,
+        );
+
+        assert!(analysis
+            .expand_macro(pos, &ExpandMacroConfig::default())
+            .unwrap()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn macro_expand_signatures_only() {
+        let config = ExpandMacroConfig { signatures_only: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn bar(x: u32) -> u32 { let y = x; y }
+                impl S { fn new() -> S { S } }
+            }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn bar(x: u32) -> u32 { /* ... */ }
+impl S {
+  fn new() -> S { /* ... */ }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_max_body_depth() {
+        let config = ExpandMacroConfig { max_body_depth: Some(1), ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn outer(x: u32) -> u32 {
+                    fn inner(y: u32) -> u32 { y + 1 }
+                    inner(x)
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn outer(x: u32) -> u32 {
+  fn inner(y: u32) -> u32 { /* ... */ }
+  inner(x)
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_reference_return_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn get<'a, T>(v: &'a mut Vec<T>) -> &'a mut Vec<T> { v }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn get<'a, T>(v: &'a mut Vec<T>) -> &'a mut Vec<T> {
+  v
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_impl_trait_return_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn iter<T>(v: Vec<T>) -> impl Iterator<Item = T> { v.into_iter() }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn iter<T>(v: Vec<T>) -> impl Iterator<Item = T> {
+  v.into_iter()
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_sibling_nested_calls_is_stable() {
+        let fixture = r#"
+        //- /lib.rs
+        macro_rules! a { () => { fn a() {} } }
+        macro_rules! b { () => { fn b() {} } }
+        macro_rules! c { () => { fn c() {} } }
+        macro_rules! foo { () => { a!(); b!(); c!(); } }
+        f<|>oo!();
+        "#;
+        let res = check_expand_macro(fixture);
+
+        // `replaces` is only ever looked up, never iterated, so hash order
+        // must not leak into the output
+        for _ in 0..10 {
+            assert_eq!(check_expand_macro(fixture).expansion, res.expansion);
+        }
+        assert_snapshot!(res.expansion, @r###"
+fn a(){}
+fn b(){}
+fn c(){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_mod_declaration() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                #[path = "x.rs"]
+                mod m;
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[path = "x.rs"]
+mod m;
+"###);
+    }
+
+    #[test]
+    fn macro_expand_inline_mod() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                mod m { fn f() {} }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+mod m {
+  fn f(){}
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_introduced_names() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn first() {}
+                fn second() {}
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.introduced_names, vec!["first".to_string(), "second".to_string()]);
+    }
+
+    #[test]
+    fn macro_expand_path_qualified_call() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        mod m {
+            #[macro_export]
+            macro_rules! bar {
+                () => { fn b() {} }
+            }
+        }
+        crate::ba<|>r!();
+        "#,
+        );
+
+        assert_eq!(res.name, "bar");
+        assert_eq!(res.qualified_name, "crate::bar!");
+        assert_snapshot!(res.expansion, @r###"fn b(){}"###);
+    }
+
+    #[test]
+    fn macro_expand_await_chain() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                async fn f() -> u32 { bar().await.baz().await? }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+async fn f() -> u32 {
+  bar().await.baz().await?
+}
+"###);
+    }
+
+    #[test]
+    fn spacing_policy_table() {
+        use SyntaxKind::*;
+
+        let policy = SpacingPolicy::default();
+        let check = |left, right, spacing| assert_eq!(policy.between(left, right), spacing);
+
+        check(FN_KW, IDENT, Spacing::Space);
+        check(IDENT, L_PAREN, Spacing::None);
+        check(R_PAREN, L_CURLY, Spacing::Space);
+        check(L_CURLY, R_CURLY, Spacing::None);
+        check(L_CURLY, LET_KW, Spacing::Newline);
+        check(R_CURLY, FN_KW, Spacing::Newline);
+        check(R_CURLY, T![,], Spacing::None);
+        check(T![;], IDENT, Spacing::Newline);
+        check(T![,], IDENT, Spacing::Space);
+        check(T![,], T![')'], Spacing::None);
+        check(IDENT, T![::], Spacing::None);
+        check(T![::], IDENT, Spacing::None);
+        check(T![::], T![<], Spacing::None);
+        check(T![>], T![::], Spacing::None);
+        check(IDENT, T![+], Spacing::Space);
+        check(T![==], INT_NUMBER, Spacing::Space);
+        check(T![&], IDENT, Spacing::None);
+        check(LIFETIME, MUT_KW, Spacing::Space);
+        check(IDENT, T![!], Spacing::None);
+        check(R_PAREN, WHERE_KW, Spacing::Space);
+        check(R_PAREN, FN_KW, Spacing::Space);
+        check(RETURN_KW, T![::], Spacing::Space);
+        check(CRATE_KW, T![::], Spacing::None);
+        check(IF_KW, T![!], Spacing::Space);
+        check(MOVE_KW, T![|], Spacing::Space);
+        check(ASYNC_KW, T![||], Spacing::Space);
+        check(ASYNC_KW, IDENT, Spacing::Space);
+        check(IDENT, FN_KW, Spacing::Space);
+        check(T![&], RAW_KW, Spacing::None);
+        check(RAW_KW, CONST_KW, Spacing::Space);
+        check(IN_KW, T!['('], Spacing::Space);
+        check(FN_KW, T!['('], Spacing::None);
+        check(SELF_KW, T!['['], Spacing::None);
+        check(FOR_KW, T![&], Spacing::Space);
+        check(SELF_KW, T![*], Spacing::None);
+        check(DYN_KW, T![*], Spacing::None);
+    }
+
+    #[test]
+    fn macro_expand_nested_in_closure_and_async_block() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            () => { 42 }
+        }
+        macro_rules! foo {
+            () => {
+                fn f() {
+                    let c = |x: u32| { x + bar!() };
+                    let fut = async { bar!() };
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() {
+  let c = |x: u32| {
+    x + 42
+  };
+  let fut = async {
+    42
+  };
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_required_imports() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        mod collections {
+            pub struct HashMap<K, V>(K, V);
+            impl<K, V> HashMap<K, V> {
+                pub fn new() -> Self { loop {} }
+            }
+        }
+        mod m {
+            use crate::collections::HashMap;
+            macro_rules! foo {
+                () => { fn make() -> HashMap<u32, u32> { HashMap::new() } }
+            }
+            f<|>oo!();
+        }
+        "#,
+        );
+
+        let imports = analysis.expansion_required_imports(pos).unwrap().unwrap();
+        assert_eq!(imports, vec!["collections::HashMap".to_string()]);
+    }
+
+    #[test]
+    fn macro_expand_referenced_crates() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        pub struct Thing;
+        macro_rules! foo {
+            () => {
+                fn make() -> std::option::Option<Thing> { std::option::Option::None }
+            }
+        }
+        f<|>oo!();
+
+        //- /std/lib.rs
+        pub mod option {
+            pub enum Option<T> { None, Some(T) }
+        }
+        "#,
+        );
+
+        let crates = analysis.expansion_referenced_crates(pos).unwrap().unwrap();
+        assert_eq!(crates, vec!["std".to_string(), "crate".to_string()]);
+    }
+
+    #[test]
+    fn macro_expand_required_imports_of_recursive_macro() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        mod things {
+            pub struct Thing;
+        }
+        mod m {
+            use crate::things::Thing;
+            macro_rules! rec {
+                () => { fn make() -> Thing { Thing } rec!(); }
+            }
+            r<|>ec!();
+        }
+        "#,
+        );
+
+        let imports = analysis.expansion_required_imports(pos).unwrap().unwrap();
+        assert_eq!(imports, vec!["things::Thing".to_string()]);
+    }
+
+    #[test]
+    fn macro_expand_referenced_crates_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expansion_referenced_crates(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_imports_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expansion_imports(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_prelude_imports() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /main.rs
+        mod collections {
+            pub struct HashMap<K, V>(K, V);
+        }
+        mod m {
+            use crate::collections::HashMap;
+            macro_rules! foo {
+                () => { fn find(map: HashMap<u32, u32>) -> Option<u32> { None } }
+            }
+            f<|>oo!();
+        }
+
+        //- /std/lib.rs
+        #[prelude_import]
+        use prelude::*;
+
+        pub mod prelude {
+            pub enum Option<T> { None, Some(T) }
+            pub use Option::None;
+        }
+        "#,
+        );
+
+        let imports = analysis.expansion_imports(pos).unwrap().unwrap();
+        assert_eq!(
+            imports,
+            vec![
+                RequiredImport { path: "collections::HashMap".to_string(), from_prelude: false },
+                RequiredImport { path: "Option".to_string(), from_prelude: true },
+                RequiredImport { path: "None".to_string(), from_prelude: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_expand_semicolon_in_parentheses() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn f() {
+                    unknown!(a; b);
+                    let x = [0; 4];
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() {
+  unknown!(a; b);
+  let x = [0; 4];
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_ansi() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { struct Foo(u32); }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        let res = analysis.expand_macro_ansi(pos).unwrap().unwrap().unwrap();
+        assert_eq!(res, "\x1b[35mstruct\x1b[0m \x1b[33mFoo\x1b[0m(\x1b[33mu32\x1b[0m);");
+    }
+
+    #[test]
+    fn macro_expand_ansi_incomplete_call() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { struct Foo(u32); }
+        }
+        fn main() {
+            f<|>oo!(
+        }
+        "#,
+        );
+        let err = analysis.expand_macro_ansi(pos).unwrap().err();
+        assert_eq!(err, Some(ExpandMacroError("macro call is incomplete".to_string())));
+    }
+
+    #[test]
+    fn macro_expand_generic_associated_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                trait Lending {
+                    type Item<'a>: Debug where Self: 'a;
+                    fn next<'a>(&'a mut self) -> Option<Self::Item<'a>>;
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+trait Lending {
+  type Item<'a>: Debug where Self: 'a;
+  fn next<'a>(&'a mut self) -> Option<Self::Item<'a>>;
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_with_limits() {
+        let fixture = r#"
+        //- /lib.rs
+        macro_rules! bar {
+            () => { fn b() {} }
+        }
+        macro_rules! foo {
+            () => { bar!(); }
+        }
+        f<|>oo!();
+        "#;
+
+        let res = check_expand_macro(fixture);
+        assert_snapshot!(res.expansion, @r###"fn b(){}"###);
+
+        let limits = ExpansionLimits { depth: 0, ..ExpansionLimits::default() };
+        let config = ExpandMacroConfig { limits, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(fixture, &config);
+        assert_snapshot!(res.expansion, @r###"bar!();"###);
+
+        let limits = ExpansionLimits { tokens: 3, ..ExpansionLimits::default() };
+        let config = ExpandMacroConfig { limits, ..ExpandMacroConfig::default() };
+        let (analysis, pos) = analysis_and_position(fixture);
+        assert!(analysis.expand_macro(pos, &config).unwrap().unwrap().is_none());
+    }
+
+    #[test]
+    fn macro_expand_yield() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! gen {
+            ($e:expr) => { || { yield $e; yield (1, $e); yield -$e; yield; } }
+        }
+        fn main() {
+            let g = gen<|>!(x);
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "gen");
+        assert_snapshot!(res.expansion, @r###"
+|| {
+  yield x;
+  yield (1, x);
+  yield -x;
+  yield;
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_include_raw() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn bar() -> u32 { 0 } }
+        }
+        f<|>oo!();
+        "#,
+        );
+        assert!(res.raw.is_none());
+
+        let config = ExpandMacroConfig { include_raw: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn bar() -> u32 { 0 } }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.raw.unwrap(), @r###"fnbar()->u32{0}"###);
+        assert_snapshot!(res.expansion, @r###"
+fn bar() -> u32 {
+  0
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_cfg_match_arms() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! os {
+            () => {
+                fn os(x: Os) -> u32 {
+                    #[cfg(debug_assertions)] log();
+                    let v = f(#[cfg(unix)] 1, 2);
+                    match x {
+                        #[cfg(unix)] Os::Unix => v,
+                        #[cfg(windows)] Os::Windows => { 2 }
+                        #[cfg(not(any(unix, windows)))] _ => 3,
+                    }
+                }
+            }
+        }
+        o<|>s!();
+        "#,
+        );
+
+        assert_eq!(res.name, "os");
+        assert_snapshot!(res.expansion, @r###"
+fn os(x: Os) -> u32 {
+  #[cfg(debug_assertions)] log();
+  let v = f(#[cfg(unix)] 1, 2);
+  match x {
+    #[cfg(unix)] Os::Unix => v,
+    #[cfg(windows)] Os::Windows => {
+      2
+    }
+    #[cfg(not(any(unix, windows)))] _ => 3,
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_incomplete_call() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        fn main() {
+            f<|>oo!(
+        }
+        "#,
+        );
+        let err = analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().err();
+        assert_eq!(err, Some(ExpandMacroError("macro call is incomplete".to_string())));
+    }
+
+    #[test]
+    fn macro_expand_tuple_struct() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! point {
+            ($t:ty) => { pub struct Point(pub $t, $t); }
+        }
+        po<|>int!(i32);
+        "#,
+        );
+
+        assert_eq!(res.name, "point");
+        assert_snapshot!(res.expansion, @r###"pub struct Point(pub i32, i32);"###);
+    }
+
+    #[test]
+    fn macro_expand_impl_fn_with_bounds() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! handler {
+            ($name:ident) => { fn $name() -> impl Fn() + Send + 'static { || {} } }
+        }
+        hand<|>ler!(make);
+        "#,
+        );
+
+        assert_eq!(res.name, "handler");
+        assert_snapshot!(res.expansion, @r###"
+fn make() -> impl Fn() + Send + 'static {
+  || {}
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_inline_const_block() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! buf {
+            ($n:expr) => { struct Buf([u8; const { $n }]); }
+        }
+        b<|>uf!(N);
+        "#,
+        );
+
+        assert_eq!(res.name, "buf");
+        assert_snapshot!(res.expansion, @r###"struct Buf([u8; const { N }]);"###);
+    }
+
+    #[test]
+    fn macro_expand_const_block_with_statements() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! size {
+            ($n:expr) => { fn size() -> usize { const { let x = $n; x + 1 } } }
+        }
+        s<|>ize!(N);
+        "#,
+        );
+
+        assert_eq!(res.name, "size");
+        assert_snapshot!(res.expansion, @r###"
+fn size() -> usize {
+  const {
+    let x = N;
+    x + 1
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_side_by_side() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        f<|>oo!();
+        "#,
+        );
+        let (original, expansion) =
+            analysis.expand_macro_side_by_side(pos).unwrap().unwrap().unwrap();
+        assert_eq!(original, "foo!();");
+        assert_eq!(expansion, "fn b(){}");
+    }
+
+    #[test]
+    fn macro_expand_side_by_side_incomplete_call() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        fn main() {
+            f<|>oo!(
+        }
+        "#,
+        );
+        let err = analysis.expand_macro_side_by_side(pos).unwrap().err();
+        assert_eq!(err, Some(ExpandMacroError("macro call is incomplete".to_string())));
+    }
+
+    #[test]
+    fn macro_expand_await_try_chain() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! chain {
+            () => { async fn f() -> Result<u32, E> { Ok(foo().await?.bar()) } }
+        }
+        ch<|>ain!();
+        "#,
+        );
+
+        assert_eq!(res.name, "chain");
+        assert_snapshot!(res.expansion, @r###"
+async fn f() -> Result<u32, E> {
+  Ok(foo().await?.bar())
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_rustfmt_skip() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! table {
+            ($name:ident) => {
+                #[rustfmt::skip]
+                const $name: [[u8; 3]; 2] = [
+                    [1,  2,  3],
+                    [10, 20, 30],
+                ];
+                fn f() -> u8 { 0 }
+            }
+        }
+        ta<|>ble!(TABLE);
+        "#,
+        );
+
+        assert_eq!(res.name, "table");
+        assert_snapshot!(res.expansion, @r###"
+#[rustfmt::skip]
+const TABLE: [[u8; 3]; 2] = [
+    [1,  2,  3],
+    [10, 20, 30],
+];
+fn f() -> u8 {
+  0
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_where_clause_assoc_type_bound() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! sum_impl {
+            ($t:ident) => {
+                impl<T> Sum for $t<T> where T: Iterator<Item = u32>, T::Item: Copy {
+                    fn sum(self) -> u32 { 0 }
+                }
+            }
+        }
+        sum_i<|>mpl!(Wrapper);
+        "#,
+        );
+
+        assert_eq!(res.name, "sum_impl");
+        assert_snapshot!(res.expansion, @r###"
+impl<T> Sum for Wrapper<T> where T: Iterator<Item = u32>, T::Item: Copy {
+  fn sum(self) -> u32 {
+    0
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_inside_other_macro_arguments() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! inner {
+            () => { fn b() {} }
+        }
+        macro_rules! outer {
+            ($($tt:tt)*) => { $($tt)* }
+        }
+        outer!(in<|>ner!());
+        "#,
+        );
+
+        assert_eq!(res.name, "inner");
+        assert_snapshot!(res.expansion, @r###"fn b(){}"###);
+    }
+
+    #[test]
+    fn macro_expand_inside_unresolved_macro_arguments() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! inner {
+            () => { fn b() {} }
+        }
+        outer!(in<|>ner!());
+        "#,
+        );
+        let res = analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap();
+        assert!(res.unwrap().is_none());
+    }
+
+    #[test]
+    fn macro_expand_visibility() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! vis {
+            () => {
+                pub fn a() {}
+                pub(crate) struct B;
+                pub(super) mod c {}
+                pub(in crate::d) fn e() {}
+                pub(self) fn f() {}
+            }
+        }
+        vi<|>s!();
+        "#,
+        );
+
+        assert_eq!(res.name, "vis");
+        assert_snapshot!(res.expansion, @r###"
+pub fn a(){}
+pub(crate) struct B;
+pub(super) mod c{}
+pub(in crate::d) fn e(){}
+pub(self) fn f(){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_partial_expansion() {
+        let fixture = r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn a() {} 1 + }
+        }
+        f<|>oo!();
+        "#;
+
+        let res = check_expand_macro_with_config(
+            fixture,
+            &ExpandMacroConfig { partial: PartialExpansion::Mark, ..ExpandMacroConfig::default() },
+        );
+        assert_snapshot!(res.expansion, @r###"
+/* partial expansion */
+fn a(){}
+1 +
+"###);
+
+        let (analysis, pos) = analysis_and_position(fixture);
+        let config =
+            ExpandMacroConfig { partial: PartialExpansion::Fail, ..ExpandMacroConfig::default() };
+        let err = analysis.expand_macro(pos, &config).unwrap().err();
+        assert_eq!(err, Some(ExpandMacroError("expansion is not valid syntax".to_string())));
+    }
+
+    #[test]
+    fn macro_expand_closure_with_return_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn f() {
+                    let g = |x: u32| -> u32 { x + 1 };
+                    let h = || -> u32 { 0 };
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() {
+  let g = |x: u32| -> u32 {
+    x + 1
+  };
+  let h = || -> u32 {
+    0
+  };
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_with_definition() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        f<|>oo!();
+        "#,
+        );
+        let (definition, expanded) =
+            analysis.expand_macro_with_definition(pos).unwrap().unwrap().unwrap();
+
+        assert_eq!(definition, "macro_rules! foo {\n    () => { fn b() {} }\n}");
+        assert_snapshot!(expanded.expansion, @r###"fn b(){}"###);
+    }
+
+    #[test]
+    fn macro_expand_with_definition_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expand_macro_with_definition(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_lifetime_outlives_bound() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                impl<'a, 'b> Foo<'a, 'b> where 'a: 'b, 'b: 'a + 'static {
+                    fn f(&self) {}
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+impl<'a, 'b> Foo<'a, 'b> where 'a: 'b, 'b: 'a + 'static {
+  fn f(&self){}
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_cache_stats() {
+        let (mock, pos) = MockAnalysis::with_files_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        f<|>oo!();
+        //- /other.rs
+        fn c() {}
+        "#,
+        );
+        let other = mock.id_of("/other.rs");
+        let mut host = mock.analysis_host();
+        assert_eq!(host.analysis().macro_expansion_cache_stats(), (0, 0));
+
+        host.analysis().expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap();
+        assert_eq!(host.analysis().macro_expansion_cache_stats(), (0, 1));
+
+        // Nothing is computed or checked again within a revision
+        host.analysis().expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap();
+        assert_eq!(host.analysis().macro_expansion_cache_stats(), (0, 1));
+
+        let mut change = AnalysisChange::new();
+        change.change_file(other, Arc::new("fn d() {}".to_string()));
+        host.apply_change(change);
+        host.analysis().expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap();
+        assert_eq!(host.analysis().macro_expansion_cache_stats(), (1, 1));
+    }
+
+    #[test]
+    fn macro_expand_cache_stats_count_nested_calls() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            () => { fn b() {} }
+        }
+        macro_rules! foo {
+            () => { bar!(); }
+        }
+        fn f() {
+            f<|>oo!();
+        }
+        "#,
+        );
+        analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap();
+        assert_eq!(analysis.macro_expansion_cache_stats(), (0, 2));
+    }
+
+    #[test]
+    fn macro_expand_render_options_reuse_expansion() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() { 92 } }
+        }
+        f<|>oo!();
+        "#,
+        );
+        let res = analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap();
+        assert_snapshot!(res.unwrap().expansion, @r###"
+fn b() {
+  92
+}
+"###);
+        assert_eq!(analysis.macro_expansion_cache_stats(), (0, 1));
+
+        let config = ExpandMacroConfig { signatures_only: true, ..ExpandMacroConfig::default() };
+        let res = analysis.expand_macro(pos, &config).unwrap().unwrap();
+        assert_snapshot!(res.unwrap().expansion, @r###"fn b() { /* ... */ }"###);
+        assert_eq!(analysis.macro_expansion_cache_stats(), (0, 1));
+    }
+
+    #[test]
+    fn macro_expand_extern_crate_and_extern_fn() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! ffi {
+            () => {
+                extern crate core;
+                extern crate alloc as a;
+                extern "C" fn callback(x: i32) -> i32 { x }
+                pub unsafe extern "C" fn raw() {}
+            }
+        }
+        ff<|>i!();
+        "#,
+        );
+
+        assert_eq!(res.name, "ffi");
+        assert_snapshot!(res.expansion, @r###"
+extern crate core;
+extern crate alloc as a;
+extern "C" fn callback(x: i32) -> i32 {
+  x
+}
+pub unsafe extern "C" fn raw(){}
+"###);
+    }
+
+    #[test]
+    fn expand_try_operator() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        fn foo() -> Result<u32, ()> { Ok(0) }
+        fn bar() -> Result<u32, ()> {
+            let x = foo()<|>?;
+            Ok(x)
+        }
+        "#,
+        );
+        let res = analysis.expand_try_operator(pos).unwrap().unwrap();
+
+        assert_snapshot!(res, @r###"
+match ::core::ops::Try::branch(foo()) {
+  ::core::ops::ControlFlow::Continue(val) => val,
+  ::core::ops::ControlFlow::Break(residual) => {
+    return ::core::ops::FromResidual::from_residual(residual)
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_meta_list_attributes() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                #[inline(always)]
+                fn a() {}
+                #[must_use = "the value should be used"]
+                fn b() -> u32 { 0 }
+                #[cfg_attr(test, derive(Debug, Clone))]
+                struct C;
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[inline(always)]
+fn a(){}
+#[must_use = "the value should be used"]
+fn b() -> u32 {
+  0
+}
+#[cfg_attr(test, derive(Debug, Clone))]
+struct C;
+"###);
+    }
+
+    #[test]
+    fn macro_expand_report() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            ($i:ident) => { fn $i() {} };
+            () => { fn b() {} }
+        }
+        macro_rules! foo {
+            (x) => { bar!(x); };
+            () => { bar!(); }
+        }
+        macro_rules! baz {
+            () => { foo!(); }
+        }
+        b<|>az!();
+        "#,
+        );
+        let report = analysis.expand_macro_report(pos).unwrap().unwrap().unwrap();
+        let levels: Vec<_> = report
+            .levels
+            .iter()
+            .map(|it| (it.depth, it.name.as_str(), it.rule_index, it.expansion.as_str()))
+            .collect();
+
+        assert_eq!(
+            levels,
+            vec![
+                (0, "baz", Some(0), "foo!();"),
+                (1, "foo", Some(1), "bar!();"),
+                (2, "bar", Some(1), "fn b(){}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_expand_report_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expand_macro_report(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_fn_pointer_types() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                struct Callbacks {
+                    on_event: fn(u32, i32) -> bool,
+                    on_done: Box<dyn Fn(&str, usize) -> Option<u8>>,
+                }
+                extern "C" {
+                    fn printf(format: *const u8, ...) -> i32;
+                }
+                fn apply(f: fn(u32) -> u32, g: impl FnMut() -> ()) {}
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+struct Callbacks {
+  on_event: fn(u32, i32) -> bool,
+  on_done: Box<dyn Fn(&str, usize) -> Option<u8>>,
+}
+extern "C" {
+  fn printf(format: *const u8, ...) -> i32;
+}
+fn apply(f: fn(u32) -> u32, g: impl FnMut() -> ()){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_lint_attributes() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                #[allow(dead_code)]
+                #[deny(unsafe_code, missing_docs)]
+                struct Unused;
+                #[allow(clippy::all)]
+                impl Unused {
+                    #[warn(unused_variables)]
+                    fn f() {}
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[allow(dead_code)]
+#[deny(unsafe_code, missing_docs)]
+struct Unused;
+#[allow(clippy::all)]
+impl Unused {
+  #[warn(unused_variables)]
+  fn f(){}
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_tuple_types() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn pair() -> (u32, i32) { (1, 2) }
+                fn nested(x: ((u8, u16), u32)) -> (u8, u16) { x.0 }
+                type Single = (u32,);
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn pair() -> (u32, i32) {
+  (1, 2)
+}
+fn nested(x: ((u8, u16), u32)) -> (u8, u16) {
+  x.0
+}
+type Single = (u32,);
+"###);
+    }
+
+    #[test]
+    fn macro_expand_unit_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn unit() -> () { () }
+                fn empty() -> () {}
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn unit() -> () {
+  ()
+}
+fn empty() -> (){}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_use_rename() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                use std::collections::HashMap as Map;
+                use std::{fmt::Result as FmtResult, io::Result as IoResult};
+                use self::inner as _;
+            }
+        }
+        f<|>oo!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+use std::collections::HashMap as Map;
+use std::{fmt::Result as FmtResult, io::Result as IoResult};
+use self::inner as _;
+"###);
+    }
+
+    #[test]
+    fn macro_expand_collapse_redundant_blocks() {
+        let config =
+            ExpandMacroConfig { collapse_redundant_blocks: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn f() -> u32 {
+                    { { { 1 } } }
+                }
+                fn g() {
+                    let x = { { bar(); } };
+                    { x }
+                }
+            }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+fn f() -> u32 { /* 3 nested blocks collapsed */
+  1
+}
+fn g() {
+  let x = { /* 1 nested block collapsed */
+    bar();
+  };
+  {
+    x
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_transparent_newtype() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! newtype {
+            ($name:ident($inner:ty)) => {
+                #[repr(transparent)]
+                #[derive(Clone, Copy)]
+                pub struct $name(pub $inner);
+            }
+        }
+        new<|>type!(Meters(f64));
+        "#,
+        );
+
+        assert_eq!(res.name, "newtype");
+        assert_snapshot!(res.expansion, @r###"
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Meters(pub f64);
+"###);
+    }
+
+    #[test]
+    fn macro_expand_skipped_calls() {
+        let limits = ExpansionLimits { depth: 1, ..ExpansionLimits::default() };
+        let config = ExpandMacroConfig { limits, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            ($($tt:tt)*) => { fn b() {} }
+        }
+        macro_rules! foo {
+            () => { fn a() {} bar!(); bar!(1); }
+        }
+        macro_rules! baz {
+            () => { foo!(); }
+        }
+        b<|>az!();
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+        fn a(){}
+        bar!();
+        bar!(1);
+        "###);
+        let skipped: Vec<_> = res
+            .skipped_calls
+            .iter()
+            .map(|(range, name)| (&res.expansion[*range], name.as_str()))
+            .collect();
+        assert_eq!(skipped, vec![("bar!();", "bar"), ("bar!(1);", "bar")]);
+    }
+
+    #[test]
+    fn macro_expand_source_map() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! abs_inc {
+            ($e:expr) => { $e.abs() + 1 }
+        }
+        fn f() { let x = abs_i<|>nc!(a + b); }
+        "#,
+        );
+        let text = analysis.file_text(pos.file_id).unwrap();
+        let res =
+            analysis.expand_macro(pos, &ExpandMacroConfig::default()).unwrap().unwrap().unwrap();
+
+        assert_eq!(res.expansion, "(a + b).abs() + 1");
+        let mapped: Vec<_> = res
+            .source_map
+            .iter()
+            .map(|(output, source)| {
+                assert_eq!(&res.expansion[*output], &text[source.range]);
+                let origin = if source.range.start() > pos.offset { "call" } else { "def" };
+                (&res.expansion[*output], origin)
+            })
+            .collect();
+        assert_eq!(
+            mapped,
+            vec![
+                ("a", "call"),
+                ("+", "call"),
+                ("b", "call"),
+                (".", "def"),
+                ("abs", "def"),
+                ("(", "def"),
+                (")", "def"),
+                ("+", "def"),
+                ("1", "def"),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_expand_annotate_substitutions() {
+        let config =
+            ExpandMacroConfig { annotate_substitutions: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! inc {
+            ($x:expr) => { $x + 1 }
+        }
+        fn f() { let y = i<|>nc!(foo(1)); }
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"foo(1) /* $x = "foo(1)" */ + 1"###);
+        let mapped: Vec<_> =
+            res.source_map.iter().map(|(output, _)| &res.expansion[*output]).collect();
+        assert_eq!(mapped, vec!["foo", "(", "1", ")", "+", "1"]);
+    }
+
+    #[test]
+    fn macro_expand_doctest() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        f<|>oo!();
+        "#,
+        );
+        let res = analysis.expand_macro_doctest(pos).unwrap().unwrap().unwrap();
+        assert!(res.starts_with("```rust\n"));
+        assert!(res.ends_with("\n```"));
+        assert_snapshot!(res, @r###"
+```rust
+fn b(){}
+```
+"###);
+
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! add {
+            ($a:expr, $b:expr) => { $a + $b }
+        }
+        fn f() { let x = a<|>dd!(1, 2); }
+        "#,
+        );
+        let res = analysis.expand_macro_doctest(pos).unwrap().unwrap().unwrap();
+        assert_snapshot!(res, @r###"
+```rust
+# fn main() {
+1 + 2
+# }
+```
+"###);
+    }
+
+    #[test]
+    fn macro_expand_doctest_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expand_macro_doctest(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_ast_json() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() -> &'static str { "a\"b" } }
+        }
+        f<|>oo!();
+        "#,
+        );
+        let json = analysis.expand_macro_ast_json(pos).unwrap().unwrap().unwrap();
+        assert!(json.starts_with(r#"{"kind":"MACRO_ITEMS","children":[{"kind":"FN_DEF""#));
+        for kind in &["NAME", "PARAM_LIST", "RET_TYPE", "REFERENCE_TYPE", "BLOCK_EXPR", "LITERAL"] {
+            assert!(json.contains(&format!(r#"{{"kind":"{}","children":["#, kind)), "{}", kind);
+        }
+        assert!(json.contains(r#"{"kind":"IDENT","text":"b"}"#));
+        assert!(json.contains(r#"{"kind":"STRING","text":"\"a\\\"b\""}"#));
+        assert!(!json.contains("WHITESPACE"));
+    }
+
+    #[test]
+    fn macro_expand_ast_json_incomplete_call() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn b() {} }
+        }
+        fn main() {
+            f<|>oo!(
+        }
+        "#,
+        );
+        let err = analysis.expand_macro_ast_json(pos).unwrap().err();
+        assert_eq!(err, Some(ExpandMacroError("macro call is incomplete".to_string())));
+    }
+
+    #[test]
+    fn macro_calls_on_line() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($e:expr) => { $e }
+        }
+        fn f() {
+            let x = f<|>oo!(1) + foo!(2);
+            foo!(3);
+        }
+        "#,
+        );
+        let calls = analysis.macro_calls_on_line(pos.file_id, 4).unwrap();
+        let text = analysis.file_text(pos.file_id).unwrap();
+        let calls: Vec<_> =
+            calls.iter().map(|(range, name)| (&text[*range], name.as_str())).collect();
+        assert_eq!(calls, vec![("foo!(1)", "foo"), ("foo!(2)", "foo")]);
+
+        assert!(analysis.macro_calls_on_line(pos.file_id, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn macro_expand_wrapped_or_patterns() {
+        let config = ExpandMacroConfig { max_width: Some(30), ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! is_vowel {
+            ($c:expr) => {
+                fn is_vowel(c: char) -> bool {
+                    match $c { 'a' | 'e' | 'i' | 'o' | 'u' => true, 'y' | 'w' => false, _ => false }
+                }
+            }
+        }
+        is_vow<|>el!(c);
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+        fn is_vowel(c: char) -> bool {
+          match c {
+            'a'
+              | 'e'
+              | 'i'
+              | 'o'
+              | 'u' => true,
+            'y' | 'w' => false,
+            _ => false
+          }
+        }
+        "###);
+    }
+
+    #[test]
+    fn macro_expand_self_and_crate_paths() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        mod util {
+            pub fn next(n: u32) -> u32 { n + 1 }
+        }
+        macro_rules! counter {
+            ($name:ident) => {
+                struct $name(u32);
+                impl $name {
+                    fn new() -> Self { Self::with(0) }
+                    fn with(n: u32) -> Self { Self(n) }
+                    fn bump(&mut self) { self.0 = crate::util::next(self.0); }
+                }
+            }
+        }
+        count<|>er!(Counter);
+        "#,
+        );
+
+        assert_eq!(res.name, "counter");
+        assert_snapshot!(res.expansion, @r###"
+struct Counter(u32);
+impl Counter {
+  fn new() -> Self {
+    Self::with(0)
+  }
+  fn with(n: u32) -> Self {
+    Self(n)
+  }
+  fn bump(&mut self) {
+    self.0 = crate::util::next(self.0);
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_raw_identifier_name() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! r#match {
+            ($e:expr) => { fn matched() -> bool { $e } }
+        }
+        r#ma<|>tch!(true);
+        "#,
+        );
+
+        assert_eq!(res.name, "match");
+        assert_eq!(res.qualified_name, "r#match!");
+        assert_snapshot!(res.expansion, @r###"
+fn matched() -> bool {
+  true
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_early_return_guard() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! ensure {
+            ($cond:expr, $err:expr) => {
+                if !$cond { return Err($err); }
+            }
+        }
+        fn check(n: u32) -> Result<(), String> {
+            ens<|>ure!(n > 0, "n must be positive".to_string());
+            Ok(())
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "ensure");
+        assert_snapshot!(res.expansion, @r###"
+if !(n > 0) {
+  return Err("n must be positive".to_string());
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_struct_pattern_with_rest() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        struct Point { x: i32, y: i32, z: i32 }
+        macro_rules! is_flat {
+            () => {
+                fn is_flat(p: Point) -> bool {
+                    let Point { z, .. } = p;
+                    match p {
+                        Point { x, y: 0, .. } => x == 0 && z == 0,
+                        Point { .. } => false,
+                    }
+                }
+            }
+        }
+        is_fl<|>at!();
+        "#,
+        );
+
+        assert_eq!(res.name, "is_flat");
+        assert_snapshot!(res.expansion, @r###"
+fn is_flat(p: Point) -> bool {
+  let Point { z, .. } = p;
+  match p {
+    Point { x, y: 0, .. } => x == 0 && z == 0,
+    Point { .. } => false,
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_render_parse_errors() {
+        let config = ExpandMacroConfig { validate: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn f() { let x = 1; } fn }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+        fn f() {
+          let x = 1;
+        }
+        fn
+        "###);
+        assert_eq!(
+            res.render_parse_errors,
+            vec!["expected a name", "expected function arguments", "expected a block"]
+        );
+
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => { fn f() { let x = 1; } }
+        }
+        f<|>oo!();
+        "#,
+            &config,
+        );
+
+        assert!(res.render_parse_errors.is_empty());
+    }
+
+    #[test]
+    fn macro_expand_const_generic_default() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! buffer {
+            ($name:ident) => {
+                struct $name<T, const N: usize = 4> { items: [T; N] }
+                impl<T, const N: usize> $name<T, N> {
+                    fn capacity(&self) -> usize { N }
+                }
+            }
+        }
+        buf<|>fer!(Buffer);
+        "#,
+        );
+
+        assert_eq!(res.name, "buffer");
+        assert_snapshot!(res.expansion, @r###"
+struct Buffer<T, const N: usize = 4> {
+  items: [T; N]
+}
+impl<T, const N: usize> Buffer<T, N> {
+  fn capacity(&self) -> usize {
+    N
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_let_chain() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! both {
+            ($x:ident, $y:ident) => {
+                fn both(x: Option<u32>, y: Option<u32>) -> u32 {
+                    if let Some(a) = $x && let Some(b) = $y { a + b } else { 0 }
+                }
+            }
+        }
+        bo<|>th!(x, y);
+        "#,
+        );
+
+        assert_eq!(res.name, "both");
+        assert_snapshot!(res.expansion, @r###"
+fn both(x: Option<u32>, y: Option<u32>) -> u32 {
+  if let Some(a) = x && let Some(b) = y {
+    a + b
+  }
+  else {
+    0
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_boxed_trait_object_with_bounds() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! boxed_error {
+            ($name:ident) => {
+                fn $name<'a>(msg: &'a str) -> Box<dyn std::error::Error + Send + Sync + 'a> {
+                    msg.into()
+                }
+                fn leak() -> Box<dyn Fn() -> u32 + Send + 'static> {
+                    Box::new(|| 0)
+                }
+            }
+        }
+        boxed_er<|>ror!(make_error);
+        "#,
+        );
+
+        assert_eq!(res.name, "boxed_error");
+        assert_snapshot!(res.expansion, @r###"
+fn make_error<'a>(msg: &'a str) -> Box<dyn std::error::Error + Send + Sync + 'a> {
+  msg.into()
+}
+fn leak() -> Box<dyn Fn() -> u32 + Send + 'static> {
+  Box::new(|| 0)
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_items() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! getters {
+            ($($name:ident: $ty:ty),*) => {
+                $(fn $name() -> $ty { Default::default() })*
+            }
+        }
+        macro_rules! foo {
+            () => {
+                getters!(a: u32, b: String);
+                impl Foo {}
+            }
+        }
+        struct Foo;
+        f<|>oo!();
+        "#,
+        );
+
+        let items = analysis.expand_macro_items(pos).unwrap().unwrap();
+        assert_eq!(
+            items,
+            vec![
+                ("a".to_string(), "fn a() -> u32 {\n  Default::default()\n}".to_string()),
+                ("b".to_string(), "fn b() -> String {\n  Default::default()\n}".to_string()),
+                (String::new(), "impl Foo{}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_expand_items_growing_without_bound() {
+        let (analysis, pos) = analysis_and_position(
+            r#"
+        //- /lib.rs
+        macro_rules! grow {
+            ($($t:tt)*) => { grow!($($t)* x); }
+        }
+        g<|>row!();
+        "#,
+        );
+        let err = analysis.expand_macro_items(pos).unwrap().err();
+        assert_eq!(
+            err,
+            Some(ExpandMacroError("expansion appears to grow without bound".to_string()))
+        );
+    }
+
+    #[test]
+    fn macro_expand_float_literals_and_method_calls() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! scale {
+            ($x:expr) => {
+                fn scale(v: f64) -> f64 {
+                    let a = 1.0;
+                    let b = 1.;
+                    let c = 1.5e3;
+                    let d = 2.0f32 as f64;
+                    let e = 1.0.max(v);
+                    let f = (1.).min(v);
+                    v.abs().mul_add(a + b, c + d + e + f + $x)
+                }
+            }
+        }
+        sca<|>le!(0.5);
+        "#,
+        );
+
+        assert_eq!(res.name, "scale");
+        assert_snapshot!(res.expansion, @r###"
+fn scale(v: f64) -> f64 {
+  let a = 1.0;
+  let b = 1.;
+  let c = 1.5e3;
+  let d = 2.0f32 as f64;
+  let e = 1.0.max(v);
+  let f = (1.).min(v);
+  v.abs().mul_add(a + b, c + d + e + f + 0.5)
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_cfg_target() {
+        let mut cfg = CfgOptions::default();
+        cfg.insert_atom("windows".into());
+        cfg.insert_key_value("target_os".into(), "windows".into());
+        cfg.insert_key_value("target_arch".into(), "x86_64".into());
+        let config = ExpandMacroConfig { cfg: Some(cfg), ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! platform {
+            () => {
+                #[cfg(target_os = "windows")]
+                fn separator() -> char { '\\' }
+                #[cfg(not(target_os = "windows"))]
+                fn separator() -> char { '/' }
+                fn pointer_width() -> u32 {
+                    #[cfg(target_arch = "x86")]
+                    let width = 32;
+                    #[cfg(target_arch = "x86_64")]
+                    let width = 64;
+                    width
+                }
+            }
+        }
+        plat<|>form!();
+        "#,
+            &config,
+        );
+
+        assert_snapshot!(res.expansion, @r###"
+        #[cfg(target_os = "windows")]
+        fn separator() -> char {
+          '\\'
+        }
+        fn pointer_width() -> u32 {
+          #[cfg(target_arch = "x86_64")] let width = 64;
+          width
+        }
+        "###);
+    }
+
+    #[test]
+    fn macro_expand_raw_pointer_types() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! ffi {
+            ($name:ident) => {
+                extern "C" {
+                    fn $name(s: *const c_char, out: *mut u8, len: usize) -> *mut *const c_char;
+                }
+                unsafe fn read(p: *const u32, buf: &mut [u8], pp: &mut &u32) -> u32 { *p }
+            }
+        }
+        ff<|>i!(puts);
+        "#,
+        );
+
+        assert_eq!(res.name, "ffi");
+        assert_snapshot!(res.expansion, @r###"
+extern "C" {
+  fn puts(s: *const c_char, out: *mut u8, len: usize) -> *mut *const c_char;
+}
+unsafe fn read(p: *const u32, buf: &mut [u8], pp: &mut &u32) -> u32 {
+  *p
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_summation_loop() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! sum_to {
+            ($n:expr) => {
+                {
+                    let mut sum = 0;
+                    for i in 0..$n { sum += i; sum *= 1; }
+                    for j in (0..=$n).rev() { sum -= j; }
+                    sum
+                }
+            }
+        }
+
+        fn f() {
+            let total = sum_t<|>o!(10);
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "sum_to");
+        assert_snapshot!(res.expansion, @r###"
+{
+  let mut sum = 0;
+  for i in 0..10 {
+    sum += i;
+    sum *= 1;
+  }
+  for j in (0..=10).rev() {
+    sum -= j;
+  }
+  sum
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_reference_type_impl() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! impl_display {
+            ($t:ident) => {
+                impl std::fmt::Display for &$t {
+                    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Ok(()) }
+                }
+                impl<'a> Marker for &'a $t {}
+                impl<'a> Marker for &'a mut $t {}
+            }
+        }
+        impl_displ<|>ay!(Foo);
+        "#,
+        );
+
+        assert_eq!(res.name, "impl_display");
+        assert_snapshot!(res.expansion, @r###"
+impl std::fmt::Display for &Foo {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    Ok(())
+  }
+}
+impl<'a> Marker for &'a Foo{}
+impl<'a> Marker for &'a mut Foo{}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_cfg_gated_field() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! make_struct {
+            ($name:ident) => {
+                pub struct $name {
+                    pub a: u32,
+                    #[cfg(feature = "x")]
+                    pub b: String,
+                }
+            }
+        }
+        make_str<|>uct!(Foo);
+        "#,
+        );
+
+        assert_eq!(res.name, "make_struct");
+        assert_snapshot!(res.expansion, @r###"
+pub struct Foo {
+  pub a: u32,
+  #[cfg(feature = "x")]
+  pub b: String,
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_async_closure() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! spawn {
+            ($e:expr) => {
+                (async |x| { x + $e }, async move |x| x)
+            }
+        }
+        fn main() {
+            let fs = spa<|>wn!(1);
+        }
+        "#,
+        );
+
+        assert_eq!(res.name, "spawn");
+        assert_snapshot!(res.expansion, @r###"
+(async |x| {
+  x + 1
+}, async move |x| x)
+"###);
+    }
+
+    #[test]
+    fn macro_expand_generated_macro_rules() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! make_macro {
+            ($name:ident) => {
+                macro_rules! $name {
+                    ($v:vis $l:lifetime $p:path, #[$m:meta] $t:ty) => {};
+                    ($e:expr, $b:block) => { $b };
+                }
+            }
+        }
+        make_ma<|>cro!(gen);
+        "#,
+        );
+
+        assert_eq!(res.name, "make_macro");
+        assert_snapshot!(res.expansion, @r###"
+macro_rules! gen {
+  ($v:vis $l:lifetime $p:path, #[$m:meta] $t:ty) => {};
+  ($e:expr, $b:block) => {
+    $b
+  };
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_unit_and_empty_variants() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! make_types {
+            () => {
+                struct Unit;
+                struct Empty();
+                enum E {
+                    Unit,
+                    EmptyTuple(),
+                    Tuple(u32, String),
+                    Record { x: u32 },
+                }
+            }
+        }
+        make_ty<|>pes!();
+        "#,
+        );
+
+        assert_eq!(res.name, "make_types");
+        assert_snapshot!(res.expansion, @r###"
+struct Unit;
+struct Empty();
+enum E {
+  Unit,
+  EmptyTuple(),
+  Tuple(u32, String),
+  Record {
+    x: u32
+  },
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_never_type() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! diverge {
+            ($name:ident) => {
+                fn $name() -> ! { loop {} }
+                fn other(f: fn() -> !) -> Result<!, ()> { panic!() }
+            }
+        }
+        diver<|>ge!(forever);
+        "#,
+        );
+
+        assert_eq!(res.name, "diverge");
+        assert_snapshot!(res.expansion, @r###"
+fn forever() -> ! {
+  loop{}
+}
+fn other(f: fn() -> !) -> Result<!, ()> {
+  panic!()
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_inclusive_range_expr() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! digits {
+            ($n:expr) => { (0..=$n).chain(b'a'..=b'f').count() }
+        }
+        fn f() { let n = dig<|>its!(9); }
+        "#,
+        );
+
+        assert_eq!(res.name, "digits");
+        assert_snapshot!(res.expansion, @r###"(0..=9).chain(b'a'..=b'f').count()"###);
+    }
+
+    #[test]
+    fn macro_expand_inclusive_range_pat() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! is_lower {
+            ($c:expr) => {
+                match $c {
+                    'a'..='z' | 'ß' => true,
+                    _ => false,
+                }
+            }
+        }
+        fn f() { let b = is_lo<|>wer!('x'); }
+        "#,
+        );
+
+        assert_eq!(res.name, "is_lower");
+        assert_snapshot!(res.expansion, @r###"
+match 'x' {
+  'a'..='z' | 'ß' => true,
+  _ => false,
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_turbofish_in_all_contexts() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn f(x: Option::<u32>) -> Vec::<u32> {
+                    let v = Vec::<u32>::with_capacity(1);
+                    let n = <Vec::<u32> as Default>::default();
+                    match x {
+                        Option::<u32>::None => v,
+                        _ => n,
+                    }
+                }
+            }
+        }
+        fo<|>o!();
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f(x: Option::<u32>) -> Vec::<u32> {
+  let v = Vec::<u32>::with_capacity(1);
+  let n = <Vec::<u32> as Default>::default();
+  match x {
+    Option::<u32>::None => v,
+    _ => n,
+  }
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_type_alias_where_clause() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($name:ident, $bound:path) => {
+                type $name<T> = Vec<T> where T: $bound;
+                trait Tr { type Assoc<T>: Clone where T: $bound; }
+                impl Tr for () { type Assoc<T> = $name<T> where T: $bound; }
+            }
+        }
+        fo<|>o!(List, Copy);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+type List<T> = Vec<T> where T: Copy;
+trait Tr {
+  type Assoc<T>: Clone where T: Copy;
+}
+impl Tr for () {
+  type Assoc<T> = List<T> where T: Copy;
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_raw_ref_expr() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($x:ident, $y:ident) => {
+                fn f() {
+                    let a = &raw const $x;
+                    let b = &raw mut $y;
+                }
+            }
+        }
+        fo<|>o!(x, y);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() {
+  let a = &raw const x;
+  let b = &raw mut y;
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_array_type_impl() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($n:expr) => {
+                impl Foo for [u8; 16] { fn first(&self) -> u8 { self[0] } }
+                impl<T, const N: usize> Foo for [T; N] { const LEN: usize = N; }
+                impl Foo for [[u8; $n]; 2] {}
+            }
+        }
+        fo<|>o!(4);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+impl Foo for [u8; 16] {
+  fn first(&self) -> u8 {
+    self[0]
+  }
+}
+impl<T, const N: usize> Foo for [T; N] {
+  const LEN: usize = N;
+}
+impl Foo for [[u8; 4]; 2]{}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_exported_macro_rules() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($name:ident) => {
+                #[macro_export]
+                macro_rules! $name {
+                    ($e:expr) => { $e };
+                }
+                #[doc(hidden)]
+                #[macro_export(local_inner_macros)]
+                macro_rules! other { () => {} }
+            }
+        }
+        fo<|>o!(bar);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[macro_export]
+macro_rules! bar {
+  ($e:expr) => {
+    $e
+  };
+}
+#[doc(hidden)]
+#[macro_export(local_inner_macros)]
+macro_rules! other {
+  () => {}
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_negation() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($flag:ident) => {
+                fn f(a: bool) -> bool {
+                    let b = !$flag && !(a || !a) != !!a;
+                    if !a { return !true; }
+                    !b
+                }
+            }
+        }
+        fo<|>o!(x);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f(a: bool) -> bool {
+  let b = !x && !(a || !a) != !!a;
+  if !a {
+    return !true;
+  }
+  !b
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_unexpanded_call_remnant() {
+        let limits = ExpansionLimits { depth: 0, ..ExpansionLimits::default() };
+        let config = ExpandMacroConfig { limits, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! bar {
+            ($e:expr) => { $e };
+        }
+        macro_rules! foo {
+            ($e:expr) => {
+                fn f() -> bool {
+                    bar!($e);
+                    let v = bar![$e];
+                    !bar!(v)
+                }
+            }
+        }
+        f<|>oo!(true);
+        "#,
+            &config,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() -> bool {
+  bar!(true);
+  let v = bar![true];
+  !bar!(v)
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_gen_fn() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($name:ident) => {
+                gen fn $name() -> i32 { yield 1; }
+                async gen fn b() { yield 2; }
+            }
+        }
+        fo<|>o!(a);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+gen fn a() -> i32 {
+  yield 1;
+}
+async gen fn b() {
+  yield 2;
 }
+"###);
+    }
 
-fn expand_macro_recur(
-    sema: &Semantics<RootDatabase>,
-    macro_call: &ast::MacroCall,
-) -> Option<SyntaxNode> {
-    let mut expanded = sema.expand(macro_call)?;
+    #[test]
+    fn macro_expand_nested_match() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($x:ident, $y:ident) => {
+                match $x {
+                    A => match $y {
+                        B => 1,
+                        _ => match $y { C => 2, _ => 3 },
+                    },
+                    _ => 0,
+                }
+            }
+        }
+        fn f() { let r = fo<|>o!(x, y); }
+        "#,
+        );
 
-    let children = expanded.descendants().filter_map(ast::MacroCall::cast);
-    let mut replaces: FxHashMap<SyntaxElement, SyntaxElement> = FxHashMap::default();
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+match x {
+  A => match y {
+    B => 1,
+    _ => match y {
+      C => 2,
+      _ => 3
+    },
+  },
+  _ => 0,
+}
+"###);
+    }
 
-    for child in children.into_iter() {
-        if let Some(new_node) = expand_macro_recur(sema, &child) {
-            // Replace the whole node if it is root
-            // `replace_descendants` will not replace the parent node
-            // but `SyntaxNode::descendants include itself
-            if expanded == *child.syntax() {
-                expanded = new_node;
-            } else {
-                replaces.insert(child.syntax().clone().into(), new_node.into());
+    #[test]
+    fn macro_expand_namespaced_attribute() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($name:ident) => {
+                #[diagnostic::on_unimplemented(message = "`{Self}` is not a {}", label = "missing")]
+                pub trait $name { fn f(&self); }
             }
         }
-    }
+        fo<|>o!(Tr);
+        "#,
+        );
 
-    Some(replace_descendants(&expanded, &|n| replaces.get(n).cloned()))
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[diagnostic::on_unimplemented(message = "`{Self}` is not a {}", label = "missing")]
+pub trait Tr {
+  fn f(&self);
 }
+"###);
+    }
 
-// FIXME: It would also be cool to share logic here and in the mbe tests,
-// which are pretty unreadable at the moment.
-fn insert_whitespaces(syn: SyntaxNode) -> String {
-    use SyntaxKind::*;
-
-    let mut res = String::new();
-    let mut token_iter = syn
-        .preorder_with_tokens()
-        .filter_map(|event| {
-            if let WalkEvent::Enter(NodeOrToken::Token(token)) = event {
-                Some(token)
-            } else {
-                None
+    #[test]
+    fn macro_expand_multi_line_string_literals() {
+        let res = check_expand_macro(
+            r##"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                fn f() -> &'static str {
+                    let a = "one \
+                             two";
+                    let b = r#"first
+          second "quoted"
+        third"#;
+                    b
+                }
             }
-        })
-        .peekable();
+        }
+        fo<|>o!();
+        "##,
+        );
 
-    let mut indent = 0;
-    let mut last: Option<SyntaxKind> = None;
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() -> &'static str {
+  let a = "one \
+                     two";
+  let b = r#"first
+  second "quoted"
+third"#;
+  b
+}
+"###);
+    }
 
-    while let Some(token) = token_iter.next() {
-        let mut is_next = |f: fn(SyntaxKind) -> bool, default| -> bool {
-            token_iter.peek().map(|it| f(it.kind())).unwrap_or(default)
-        };
-        let is_last =
-            |f: fn(SyntaxKind) -> bool, default| -> bool { last.map(f).unwrap_or(default) };
-
-        res += &match token.kind() {
-            k if is_text(k) && is_next(|it| !it.is_punct(), true) => token.text().to_string() + " ",
-            L_CURLY if is_next(|it| it != R_CURLY, true) => {
-                indent += 1;
-                let leading_space = if is_last(is_text, false) { " " } else { "" };
-                format!("{}{{\n{}", leading_space, "  ".repeat(indent))
-            }
-            R_CURLY if is_last(|it| it != L_CURLY, true) => {
-                indent = indent.saturating_sub(1);
-                format!("\n{}}}", "  ".repeat(indent))
-            }
-            R_CURLY => format!("}}\n{}", "  ".repeat(indent)),
-            T![;] => format!(";\n{}", "  ".repeat(indent)),
-            T![->] => " -> ".to_string(),
-            T![=] => " = ".to_string(),
-            T![=>] => " => ".to_string(),
-            _ => token.text().to_string(),
-        };
+    #[test]
+    fn macro_expand_const_generic_method() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($t:ty) => {
+                impl Buf for $t {
+                    fn f<const N: usize>(&self, arr: [u8; N]) -> [u8; N] { arr }
+                    fn g<T, const N: usize>(&mut self, arr: &[T; N]) -> usize where T: Copy { N }
+                }
+            }
+        }
+        fo<|>o!(Vec<u8>);
+        "#,
+        );
 
-        last = Some(token.kind());
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+impl Buf for Vec<u8> {
+  fn f<const N: usize>(&self, arr: [u8; N]) -> [u8; N] {
+    arr
+  }
+  fn g<T, const N: usize>(&mut self, arr: &[T; N]) -> usize where T: Copy {
+    N
+  }
+}
+"###);
     }
 
-    return res;
+    #[test]
+    fn macro_expand_global_allocator() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            ($alloc:expr) => {
+                #[global_allocator]
+                static GLOBAL: MyAllocator = $alloc;
+                #[panic_handler]
+                fn panic(info: &PanicInfo) -> ! { abort() }
+            }
+        }
+        fo<|>o!(MyAllocator::new());
+        "#,
+        );
 
-    fn is_text(k: SyntaxKind) -> bool {
-        k.is_keyword() || k.is_literal() || k == IDENT
-    }
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+#[global_allocator]
+static GLOBAL: MyAllocator = MyAllocator::new();
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+  abort()
 }
+"###);
+    }
 
-#[cfg(test)]
-mod tests {
-    use insta::assert_snapshot;
-
-    use crate::mock_analysis::analysis_and_position;
+    #[test]
+    fn macro_expand_hygiene_collisions() {
+        let config =
+            ExpandMacroConfig { report_hygiene_collisions: true, ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! double {
+            ($e:expr) => {{ let x = 2; x + $e }};
+        }
+        fn f() {
+            let x = 3;
+            let y = dou<|>ble!(x);
+        }
+        "#,
+            &config,
+        );
 
-    use super::*;
+        assert_snapshot!(res.expansion, @r###"
+{
+  let x = 2;
+  x + x
+}
+"###);
+        assert_eq!(
+            res.hygiene_collisions,
+            vec!["`x` bound by the macro is not the `x` passed to it".to_string()]
+        );
 
-    fn check_expand_macro(fixture: &str) -> ExpandedMacro {
-        let (analysis, pos) = analysis_and_position(fixture);
-        analysis.expand_macro(pos).unwrap().unwrap()
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! double {
+            ($e:expr) => {{ let y = 2; y + $e }};
+        }
+        fn f() {
+            let x = 3;
+            let y = dou<|>ble!(x);
+        }
+        "#,
+            &config,
+        );
+        assert!(res.hygiene_collisions.is_empty());
     }
 
     #[test]
-    fn macro_expand_recursive_expansion() {
+    fn macro_expand_array_repeat() {
         let res = check_expand_macro(
             r#"
         //- /lib.rs
-        macro_rules! bar {
-            () => { fn  b() {} }
-        }
         macro_rules! foo {
-            () => { bar!(); }
+            ($x:expr, $n:expr) => {
+                fn f() -> [[u8; 4]; 2] {
+                    let a = [0u8; 16];
+                    let b = [[$x; 4]; $n];
+                    let c = &a[1..$n];
+                    b
+                }
+            }
         }
-        macro_rules! baz {
-            () => { foo!(); }
+        fo<|>o!(7, 2);
+        "#,
+        );
+
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+fn f() -> [[u8; 4]; 2] {
+  let a = [0u8; 16];
+  let b = [[7; 4]; 2];
+  let c = &a[1..2];
+  b
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_wrapped_use_groups() {
+        let config = ExpandMacroConfig { max_width: Some(30), ..ExpandMacroConfig::default() };
+        let res = check_expand_macro_with_config(
+            r#"
+        //- /lib.rs
+        macro_rules! foo {
+            () => {
+                use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+                use std::{fmt, io::{self, Read, Write, BufRead}};
+                use a::{b, c};
+            }
         }
         f<|>oo!();
         "#,
+            &config,
         );
 
         assert_eq!(res.name, "foo");
         assert_snapshot!(res.expansion, @r###"
-fn b(){}
+use std::collections::{
+  BTreeMap,
+  HashMap,
+  HashSet,
+  VecDeque
+};
+use std::{
+  fmt,
+  io::{
+    self,
+    Read,
+    Write,
+    BufRead
+  }
+};
+use a::{b, c};
 "###);
     }
 
     #[test]
-    fn macro_expand_multiple_lines() {
+    fn macro_expand_fn_trait_syntax() {
         let res = check_expand_macro(
             r#"
         //- /lib.rs
         macro_rules! foo {
-            () => {
-                fn some_thing() -> u32 {
-                    let a = 0;
-                    a + 10
+            ($name:ident) => {
+                struct $name {
+                    f: Box<dyn FnMut(u32, i32) -> bool>,
+                    h: Option<Box<dyn FnOnce(&str) -> Result<(), ()> + Send>>,
                 }
             }
         }
-        f<|>oo!();
+        fo<|>o!(S);
         "#,
         );
 
         assert_eq!(res.name, "foo");
         assert_snapshot!(res.expansion, @r###"
-fn some_thing() -> u32 {
-  let a = 0;
-  a+10
+struct S {
+  f: Box<dyn FnMut(u32, i32) -> bool>,
+  h: Option<Box<dyn FnOnce(&str) -> Result<(), ()> + Send>>,
 }
 "###);
     }
 
     #[test]
-    fn macro_expand_match_ast() {
+    fn macro_expand_param_patterns() {
         let res = check_expand_macro(
             r#"
         //- /lib.rs
-        macro_rules! match_ast {
-            (match $node:ident { $($tt:tt)* }) => { match_ast!(match ($node) { $($tt)* }) };
-
-            (match ($node:expr) {
-                $( ast::$ast:ident($it:ident) => $res:block, )*
-                _ => $catch_all:expr $(,)?
-            }) => {{
-                $( if let Some($it) = ast::$ast::cast($node.clone()) $res else )*
-                { $catch_all }
-            }};
-        }
-
-        fn main() {
-            mat<|>ch_ast! {
-                match container {
-                    ast::TraitDef(it) => {},
-                    ast::ImplBlock(it) => {},
-                    _ => { continue },
-                }
+        macro_rules! foo {
+            () => {
+                fn f(mut x: u32, (a, b): (u8, u8), ref r: String, S { z, .. }: S) -> u32 { x += 1; x }
+                impl T for U { fn g(mut self, ref mut v: Vec<u8>) -> u8 { v[0] } }
             }
         }
+        fo<|>o!();
         "#,
         );
 
-        assert_eq!(res.name, "match_ast");
+        assert_eq!(res.name, "foo");
         assert_snapshot!(res.expansion, @r###"
-{
-  if let Some(it) = ast::TraitDef::cast(container.clone()){}
-  else if let Some(it) = ast::ImplBlock::cast(container.clone()){}
-  else {
-    {
-      continue
-    }
+fn f(mut x: u32, (a, b): (u8, u8), ref r: String, S { z, .. }: S) -> u32 {
+  x += 1;
+  x
+}
+impl T for U {
+  fn g(mut self, ref mut v: Vec<u8>) -> u8 {
+    v[0]
   }
 }
 "###);
     }
 
     #[test]
-    fn macro_expand_match_ast_inside_let_statement() {
+    fn macro_expand_inherent_assoc_consts() {
         let res = check_expand_macro(
             r#"
         //- /lib.rs
-        macro_rules! match_ast {
-            (match $node:ident { $($tt:tt)* }) => { match_ast!(match ($node) { $($tt)* }) };
-            (match ($node:expr) {}) => {{}};
-        }
-
-        fn main() {
-            let p = f(|it| {
-                let res = mat<|>ch_ast! { match c {}};
-                Some(res)
-            })?;
+        macro_rules! foo {
+            ($t:ident) => {
+                impl $t {
+                    const MAX: u32 = 100;
+                    pub const NAME: &'static str = "foo";
+                }
+            }
         }
+        fo<|>o!(Foo);
         "#,
         );
 
-        assert_eq!(res.name, "match_ast");
-        assert_snapshot!(res.expansion, @r###"{}"###);
+        assert_eq!(res.name, "foo");
+        assert_snapshot!(res.expansion, @r###"
+impl Foo {
+  const MAX: u32 = 100;
+  pub const NAME: &'static str = "foo";
+}
+"###);
     }
 
     #[test]
-    fn macro_expand_inner_macro_fail_to_expand() {
+    fn macro_expand_qualified_self_calls() {
         let res = check_expand_macro(
             r#"
         //- /lib.rs
-        macro_rules! bar {
-            (BAD) => {};
-        }
         macro_rules! foo {
-            () => {bar!()};
-        }
-
-        fn main() {
-            let res = fo<|>o!();
+            ($t:ident) => {
+                impl $t {
+                    fn f(&self) -> u32 {
+                        let a = Self::helper(self);
+                        a + <Self as Trait>::method()
+                    }
+                }
+            }
         }
+        fo<|>o!(Foo);
         "#,
         );
 
         assert_eq!(res.name, "foo");
-        assert_snapshot!(res.expansion, @r###"bar!()"###);
+        assert_snapshot!(res.expansion, @r###"
+impl Foo {
+  fn f(&self) -> u32 {
+    let a = Self::helper(self);
+    a + <Self as Trait>::method()
+  }
+}
+"###);
     }
 
     #[test]
-    fn macro_expand_with_dollar_crate() {
-        let res = check_expand_macro(
-            r#"
+    fn macro_expand_canonical_render_mode() {
+        let config = ExpandMacroConfig {
+            render_mode: RenderMode::Canonical,
+            ..ExpandMacroConfig::default()
+        };
+        let compact = r#"
         //- /lib.rs
-        #[macro_export]
-        macro_rules! bar {
-            () => {0};
+        macro_rules! table {
+            ($name:ident) => {
+                #[rustfmt::skip]
+                const $name: [u8; 3] = [1, 2, 3];
+                fn f() -> u8 { 0 }
+            }
         }
-        macro_rules! foo {
-            () => {$crate::bar!()};
+        ta<|>ble!(TABLE);
+        "#;
+        let aligned = r#"
+        //- /lib.rs
+        macro_rules! table {
+            ( $name:ident ) => {
+                #[rustfmt::skip]
+                const $name: [u8; 3] = [
+                    1,  2,  3 // digits
+                ];
+                fn f()
+                    -> u8
+                { 0 }
+            }
         }
+        ta<|>ble!(TABLE);
+        "#;
+        let default = ExpandMacroConfig::default();
+        assert_ne!(
+            check_expand_macro_with_config(compact, &default).expansion,
+            check_expand_macro_with_config(aligned, &default).expansion
+        );
 
-        fn main() {
-            let res = fo<|>o!();
+        let res = check_expand_macro_with_config(compact, &config);
+        assert_eq!(res.expansion, check_expand_macro_with_config(aligned, &config).expansion);
+        assert_snapshot!(res.expansion, @r###"
+#[rustfmt::skip]
+const TABLE: [u8; 3] = [1, 2, 3];
+fn f() -> u8 {
+  0
+}
+"###);
+    }
+
+    #[test]
+    fn macro_expand_export_name_attributes() {
+        let res = check_expand_macro(
+            r#"
+        //- /lib.rs
+        macro_rules! export {
+            ($name:ident, $sym:literal) => {
+                #[no_mangle]
+                #[export_name = $sym]
+                pub extern "C" fn $name(x: i32) -> i32 { x + 1 }
+            }
         }
+        exp<|>ort!(bump, "foo");
         "#,
         );
 
-        assert_eq!(res.name, "foo");
-        assert_snapshot!(res.expansion, @r###"0"###);
+        assert_eq!(res.name, "export");
+        assert_snapshot!(res.expansion, @r###"
+#[no_mangle]
+#[export_name = "foo"]
+pub extern "C" fn bump(x: i32) -> i32 {
+  x + 1
+}
+"###);
     }
 }
@@ -64,7 +64,10 @@ pub use crate::{
     completion::{CompletionItem, CompletionItemKind, InsertTextFormat},
     diagnostics::Severity,
     display::{file_structure, FunctionSignature, NavigationTarget, StructureNode},
-    expand_macro::ExpandedMacro,
+    expand_macro::{
+        ExpandMacroConfig, ExpandMacroError, ExpandedMacro, ExpansionLevel, ExpansionLimits,
+        ExpansionReport, PartialExpansion, RenderMode, RequiredImport,
+    },
     folding_ranges::{Fold, FoldKind},
     hover::HoverResult,
     inlay_hints::{InlayHint, InlayKind},
@@ -267,8 +270,125 @@ impl Analysis {
         self.with_db(|db| syntax_tree::syntax_tree(&db, file_id, text_range))
     }
 
-    pub fn expand_macro(&self, position: FilePosition) -> Cancelable<Option<ExpandedMacro>> {
-        self.with_db(|db| expand_macro::expand_macro(db, position))
+    /// Expands the macro call at the given position, recursively expanding
+    /// the macro calls it produces. Fails if the call is still incomplete.
+    pub fn expand_macro(
+        &self,
+        position: FilePosition,
+        config: &ExpandMacroConfig,
+    ) -> Cancelable<Result<Option<ExpandedMacro>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro(db, position, config))
+    }
+
+    /// Expands the macro call at the given position like `expand_macro` does,
+    /// but highlights the result with ANSI color codes.
+    pub fn expand_macro_ansi(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<String>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_ansi(db, position))
+    }
+
+    /// Expands the macro call at the given position like `expand_macro` does,
+    /// but renders the result as a code block for documentation.
+    pub fn expand_macro_doctest(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<String>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_doctest(db, position))
+    }
+
+    /// Serializes the expansion of the macro call at the given position as a
+    /// JSON tree of syntax nodes and tokens.
+    pub fn expand_macro_ast_json(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<String>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_ast_json(db, position))
+    }
+
+    /// Lists the macro calls starting on the given zero-based line, with
+    /// their ranges and names.
+    pub fn macro_calls_on_line(
+        &self,
+        file_id: FileId,
+        line: u32,
+    ) -> Cancelable<Vec<(TextRange, String)>> {
+        self.with_db(|db| expand_macro::macro_calls_on_line(db, file_id, line))
+    }
+
+    /// Returns the text of the macro call at the given position and its
+    /// expansion, for showing them side by side.
+    pub fn expand_macro_side_by_side(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<(String, String)>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_side_by_side(db, position))
+    }
+
+    /// Returns the source of the macro definition the call at the given
+    /// position resolves to, together with the expansion of the call.
+    pub fn expand_macro_with_definition(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<(String, ExpandedMacro)>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_with_definition(db, position))
+    }
+
+    /// Renders each item the macro call at the given position expands to on
+    /// its own, together with its name.
+    pub fn expand_macro_items(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Vec<(String, String)>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_items(db, position))
+    }
+
+    /// Expands the macro call at the given position one level at a time,
+    /// reporting the rule each call is expanded with.
+    pub fn expand_macro_report(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Option<ExpansionReport>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expand_macro_report(db, position))
+    }
+
+    /// Returns the desugaring of the `?` operator at the given position.
+    pub fn expand_try_operator(&self, position: FilePosition) -> Cancelable<Option<String>> {
+        self.with_db(|db| expand_macro::expand_try_operator(db, position))
+    }
+
+    /// Returns how many macro expansions were reused from an earlier revision
+    /// (hits) and how many had to be computed (misses).
+    pub fn macro_expansion_cache_stats(&self) -> (u64, u64) {
+        self.db.macro_expansion_stats.get()
+    }
+
+    /// Returns the paths the expansion of the macro call at the given position
+    /// needs to import when it is used outside of the macro.
+    pub fn expansion_required_imports(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Vec<String>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expansion_required_imports(db, position))
+    }
+
+    /// Like `expansion_required_imports`, also telling which of the paths
+    /// come from the prelude and so need no import.
+    pub fn expansion_imports(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Vec<RequiredImport>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expansion_imports(db, position))
+    }
+
+    /// Returns the crates defining the items the expansion of the macro call
+    /// at the given position refers to, named as the calling crate sees them.
+    pub fn expansion_referenced_crates(
+        &self,
+        position: FilePosition,
+    ) -> Cancelable<Result<Vec<String>, ExpandMacroError>> {
+        self.with_db(|db| expand_macro::expansion_referenced_crates(db, position))
     }
 
     /// Returns an edit to remove all newlines in the range, cleaning up minor
@@ -179,8 +179,6 @@ fn highlight_node(
             bindings_shadow_count.clear();
             return None;
         }
-        COMMENT => HighlightTag::LITERAL_COMMENT,
-        STRING | RAW_STRING | RAW_BYTE_STRING | BYTE_STRING => HighlightTag::LITERAL_STRING,
         ATTR => HighlightTag::LITERAL_ATTRIBUTE,
         // Special-case field init shorthand
         NAME_REF if node.parent().and_then(ast::RecordField::cast).is_some() => HighlightTag::FIELD,
@@ -225,15 +223,7 @@ fn highlight_node(
                 }),
             }
         }
-        INT_NUMBER | FLOAT_NUMBER => HighlightTag::LITERAL_NUMERIC,
-        BYTE => HighlightTag::LITERAL_BYTE,
-        CHAR => HighlightTag::LITERAL_CHAR,
-        LIFETIME => HighlightTag::TYPE_LIFETIME,
-        T![unsafe] => HighlightTag::KEYWORD_UNSAFE,
-        k if is_control_keyword(k) => HighlightTag::KEYWORD_CONTROL,
-        k if k.is_keyword() => HighlightTag::KEYWORD,
-
-        _ => return None,
+        k => highlight_kind(k)?,
     };
 
     return Some((tag, binding_hash));
@@ -251,6 +241,47 @@ fn highlight_node(
     }
 }
 
+/// Classifies a token of a tree which is not backed by a file, like a
+/// rendered macro expansion, where no semantic information is available.
+pub(crate) fn highlight_token_syntactically(token: &SyntaxToken) -> Option<HighlightTag> {
+    if token.kind() != IDENT {
+        return highlight_kind(token.kind());
+    }
+    let parent = token.parent();
+    let grand_parent = parent.parent()?;
+    match (parent.kind(), grand_parent.kind()) {
+        (NAME, STRUCT_DEF) | (NAME, ENUM_DEF) | (NAME, TRAIT_DEF) | (NAME, TYPE_ALIAS_DEF) => {
+            Some(HighlightTag::TYPE)
+        }
+        (NAME, TYPE_PARAM) => Some(HighlightTag::TYPE_PARAM),
+        (NAME_REF, PATH_SEGMENT) => {
+            let path = grand_parent.parent()?;
+            if path.parent()?.kind() == PATH_TYPE {
+                Some(HighlightTag::TYPE)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn highlight_kind(kind: SyntaxKind) -> Option<HighlightTag> {
+    let tag = match kind {
+        COMMENT => HighlightTag::LITERAL_COMMENT,
+        STRING | RAW_STRING | RAW_BYTE_STRING | BYTE_STRING => HighlightTag::LITERAL_STRING,
+        INT_NUMBER | FLOAT_NUMBER => HighlightTag::LITERAL_NUMERIC,
+        BYTE => HighlightTag::LITERAL_BYTE,
+        CHAR => HighlightTag::LITERAL_CHAR,
+        LIFETIME => HighlightTag::TYPE_LIFETIME,
+        T![unsafe] => HighlightTag::KEYWORD_UNSAFE,
+        k if is_control_keyword(k) => HighlightTag::KEYWORD_CONTROL,
+        k if k.is_keyword() => HighlightTag::KEYWORD,
+        _ => return None,
+    };
+    Some(tag)
+}
+
 pub(crate) fn highlight_as_html(db: &RootDatabase, file_id: FileId, rainbow: bool) -> String {
     let parse = db.parse(file_id);
 
@@ -11,8 +11,12 @@ pub mod defs;
 pub mod imports_locator;
 mod wasm_shims;
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
+use hir::db::AstDatabaseGroupKey__;
 use ra_db::{
     salsa::{self, Database, Durability},
     Canceled, CheckCanceled, CrateId, FileId, FileLoader, FileLoaderDelegate, RelativePath,
@@ -37,6 +41,7 @@ pub struct RootDatabase {
     runtime: salsa::Runtime<RootDatabase>,
     pub feature_flags: Arc<FeatureFlags>,
     pub(crate) debug_data: Arc<DebugData>,
+    pub macro_expansion_stats: Arc<MacroExpansionStats>,
     pub last_gc: crate::wasm_shims::Instant,
     pub last_gc_check: crate::wasm_shims::Instant,
 }
@@ -69,9 +74,17 @@ impl salsa::Database for RootDatabase {
     }
     fn salsa_event(&self, event: impl Fn() -> salsa::Event<RootDatabase>) {
         match event().kind {
-            salsa::EventKind::DidValidateMemoizedValue { .. }
-            | salsa::EventKind::WillExecute { .. } => {
+            salsa::EventKind::DidValidateMemoizedValue { database_key } => {
+                self.check_canceled();
+                if is_macro_expansion(&database_key) {
+                    self.macro_expansion_stats.record(true);
+                }
+            }
+            salsa::EventKind::WillExecute { database_key } => {
                 self.check_canceled();
+                if is_macro_expansion(&database_key) {
+                    self.macro_expansion_stats.record(false);
+                }
             }
             _ => (),
         }
@@ -92,6 +105,7 @@ impl RootDatabase {
             last_gc_check: crate::wasm_shims::Instant::now(),
             feature_flags: Arc::new(feature_flags),
             debug_data: Default::default(),
+            macro_expansion_stats: Default::default(),
         };
         db.set_crate_graph_with_durability(Default::default(), Durability::HIGH);
         db.set_local_roots_with_durability(Default::default(), Durability::HIGH);
@@ -104,6 +118,15 @@ impl RootDatabase {
     }
 }
 
+/// Whether `key` is the key of `parse_macro`, which both name resolution and
+/// the IDE read the expansions of macro calls through.
+fn is_macro_expansion(key: &__SalsaDatabaseKey) -> bool {
+    match &key.kind {
+        __SalsaDatabaseKeyKind::AstDatabaseStorage(AstDatabaseGroupKey__::parse_macro(_)) => true,
+        _ => false,
+    }
+}
+
 impl salsa::ParallelDatabase for RootDatabase {
     fn snapshot(&self) -> salsa::Snapshot<RootDatabase> {
         salsa::Snapshot::new(RootDatabase {
@@ -112,6 +135,7 @@ impl salsa::ParallelDatabase for RootDatabase {
             last_gc_check: self.last_gc_check,
             feature_flags: Arc::clone(&self.feature_flags),
             debug_data: Arc::clone(&self.debug_data),
+            macro_expansion_stats: Arc::clone(&self.macro_expansion_stats),
         })
     }
 }
@@ -138,3 +162,25 @@ impl DebugData {
         self.crate_names.extend(other.crate_names.into_iter());
     }
 }
+
+/// Counts the macro expansions reused from an earlier revision after checking
+/// their inputs (hits) and the ones that had to be computed (misses). Salsa
+/// reports nothing when a value already checked in the current revision is
+/// read again, so such reads aren't counted.
+#[derive(Debug, Default)]
+pub struct MacroExpansionStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl MacroExpansionStats {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of hits and misses.
+    pub fn get(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
@@ -11,6 +11,9 @@ mod subtree_source;
 
 pub use tt::{Delimiter, Punct};
 
+use ra_parser::FragmentKind;
+use ra_syntax::{ast, AstNode, SmolStr, SyntaxNode};
+
 use crate::{
     parser::{parse_pattern, Op},
     tt_iter::TtIter,
@@ -32,8 +35,33 @@ pub enum ExpandError {
 
 pub use crate::syntax_bridge::{
     ast_to_token_tree, syntax_node_to_token_tree, token_tree_to_syntax_node, TokenMap,
+    TokenTextRange,
 };
 
+/// Expands a macro call using only syntax trees, without any database.
+///
+/// `def` is the `macro_rules!` definition and `call` the call to expand. As no
+/// name resolution happens, nested macro calls are left as is and `$crate` is
+/// not resolved. Returns the expanded tree together with its text, rendered
+/// from the token tree.
+pub fn expand_detached(
+    def: &SyntaxNode,
+    call: &SyntaxNode,
+    fragment_kind: FragmentKind,
+) -> Result<(SyntaxNode, String), ExpandError> {
+    let token_tree = |node: &SyntaxNode| {
+        let tt = ast::MacroCall::cast(node.clone())?.token_tree()?;
+        Some(ast_to_token_tree(&tt)?.0)
+    };
+    let def_tt = token_tree(def).ok_or(ExpandError::ConversionError)?;
+    let call_tt = token_tree(call).ok_or(ExpandError::ConversionError)?;
+
+    let rules = MacroRules::parse(&def_tt).map_err(|_| ExpandError::ConversionError)?;
+    let expanded = rules.expand(&call_tt)?;
+    let (parse, _) = token_tree_to_syntax_node(&expanded, fragment_kind)?;
+    Ok((parse.syntax_node(), expanded.to_string()))
+}
+
 /// This struct contains AST for a single `macro_rules` definition. What might
 /// be very confusing is that AST has almost exactly the same shape as
 /// `tt::TokenTree`, but there's a crucial difference: in macro rules, `$ident`
@@ -76,12 +104,18 @@ impl Shift {
                             _ => tree_id,
                         }
                     }
-                    tt::TokenTree::Leaf(tt::Leaf::Ident(ident))
-                        if ident.id != tt::TokenId::unspecified() =>
-                    {
-                        Some(ident.id.0)
+                    tt::TokenTree::Leaf(leaf) => {
+                        let id = match leaf {
+                            tt::Leaf::Ident(it) => it.id,
+                            tt::Leaf::Punct(it) => it.id,
+                            tt::Leaf::Literal(it) => it.id,
+                        };
+                        if id != tt::TokenId::unspecified() {
+                            Some(id.0)
+                        } else {
+                            None
+                        }
                     }
-                    _ => None,
                 })
                 .max()
         }
@@ -156,6 +190,18 @@ impl MacroRules {
         mbe_expander::expand(self, &tt)
     }
 
+    /// Returns the index of the rule which `expand` would use for the given
+    /// input.
+    pub fn matching_rule(&self, tt: &tt::Subtree) -> Option<usize> {
+        mbe_expander::matching_rule(self, tt)
+    }
+
+    /// Returns the metavariables bound outside of repetitions when expanding
+    /// the given input, together with what they are bound to.
+    pub fn simple_bindings(&self, tt: &tt::Subtree) -> Vec<(SmolStr, tt::TokenTree)> {
+        mbe_expander::simple_bindings(self, tt)
+    }
+
     pub fn map_id_down(&self, id: tt::TokenId) -> tt::TokenId {
         self.shift.shift(id)
     }
@@ -17,6 +17,36 @@ pub(crate) fn expand(
     rules.rules.iter().find_map(|it| expand_rule(it, input).ok()).ok_or(ExpandError::NoMatchingRule)
 }
 
+pub(crate) fn matching_rule(rules: &crate::MacroRules, input: &tt::Subtree) -> Option<usize> {
+    rules.rules.iter().position(|it| expand_rule(it, input).is_ok())
+}
+
+/// Returns the metavariables bound outside of repetitions by the rule which
+/// `expand` would use, sorted by name.
+pub(crate) fn simple_bindings(
+    rules: &crate::MacroRules,
+    input: &tt::Subtree,
+) -> Vec<(SmolStr, tt::TokenTree)> {
+    let bindings = rules.rules.iter().find_map(|it| {
+        let bindings = matcher::match_(&it.lhs, input).ok()?;
+        transcriber::transcribe(&it.rhs, &bindings).ok()?;
+        Some(bindings)
+    });
+    let mut res: Vec<_> = bindings
+        .map(|it| it.inner)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(name, binding)| match binding {
+            Binding::Fragment(Fragment::Tokens(it)) | Binding::Fragment(Fragment::Ast(it)) => {
+                Some((name, it))
+            }
+            Binding::Nested(_) | Binding::Empty => None,
+        })
+        .collect();
+    res.sort_by(|(a, _), (b, _)| a.cmp(b));
+    res
+}
+
 fn expand_rule(rule: &crate::Rule, input: &tt::Subtree) -> Result<tt::Subtree, ExpandError> {
     let bindings = matcher::match_(&rule.lhs, input)?;
     let res = transcriber::transcribe(&rule.rhs, &bindings)?;
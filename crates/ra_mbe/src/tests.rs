@@ -1,7 +1,11 @@
 use std::fmt::Write;
 
 use ra_parser::FragmentKind;
-use ra_syntax::{ast, AstNode, NodeOrToken, SyntaxKind::IDENT, SyntaxNode, WalkEvent, T};
+use ra_syntax::{
+    ast, AstNode, NodeOrToken,
+    SyntaxKind::{IDENT, MACRO_CALL, MACRO_ITEMS},
+    SyntaxNode, WalkEvent, T,
+};
 use test_utils::assert_eq_text;
 
 use super::*;
@@ -91,6 +95,32 @@ macro_rules! foobar {
     assert_eq!(get_id(&expansion.token_trees[2]), Some(14));
 }
 
+#[test]
+fn test_token_id_shift_past_puncts_and_literals() {
+    let expansion = parse_macro(
+        r#"
+macro_rules! foobar {
+    ($e:ident) => { $e + 1 }
+}
+"#,
+    )
+    .expand_tt("foobar!(baz);");
+
+    fn get_id(t: &tt::TokenTree) -> Option<u32> {
+        match t {
+            tt::TokenTree::Leaf(tt::Leaf::Ident(it)) => Some(it.id.0),
+            tt::TokenTree::Leaf(tt::Leaf::Punct(it)) => Some(it.id.0),
+            tt::TokenTree::Leaf(tt::Leaf::Literal(it)) => Some(it.id.0),
+            _ => None,
+        }
+    }
+
+    let ids: Vec<u32> = expansion.token_trees.iter().filter_map(get_id).collect();
+    assert_eq!(ids.len(), 3);
+    // `baz` comes from the call, so it must not share an id with `+` or `1`
+    assert!(ids[0] > ids[1] && ids[0] > ids[2]);
+}
+
 #[test]
 fn test_token_map() {
     let expanded = parse_macro(
@@ -118,6 +148,62 @@ macro_rules! foobar {
     assert_eq!(get_text(tt::TokenId(13), T!['{']), "{");
 }
 
+#[test]
+fn test_matching_rule() {
+    let rules = parse_macro(
+        r#"
+macro_rules! foo {
+    (bar) => { 0 };
+    ($e:ident) => { 1 };
+    ($($e:expr),*) => { 2 };
+}
+"#,
+    );
+
+    assert_eq!(rules.matching_rule("foo!(bar);"), Some(0));
+    assert_eq!(rules.matching_rule("foo!(baz);"), Some(1));
+    assert_eq!(rules.matching_rule("foo!(1, 2);"), Some(2));
+    assert_eq!(rules.matching_rule("foo!(;);"), None);
+}
+
+#[test]
+fn test_simple_bindings() {
+    let rules = parse_macro(
+        r#"
+macro_rules! foo {
+    (bar) => { 0 };
+    ($i:ident, $e:expr $(, $r:tt)*) => { 1 };
+}
+"#,
+    );
+
+    assert_eq!(rules.simple_bindings("foo!(bar);"), vec![]);
+    assert_eq!(
+        rules.simple_bindings("foo!(x, 1 + 2, a, b);"),
+        vec![("e".to_string(), "1 + 2".to_string()), ("i".to_string(), "x".to_string())]
+    );
+}
+
+#[test]
+fn test_expand_detached() {
+    let source_file = ast::SourceFile::parse(
+        r#"
+macro_rules! foo {
+    ($i:ident) => { fn $i() {} }
+}
+foo!(bar);
+"#,
+    )
+    .tree();
+    let mut calls = source_file.syntax().descendants().filter(|it| it.kind() == MACRO_CALL);
+    let def = calls.next().unwrap();
+    let call = calls.next().unwrap();
+
+    let (node, text) = expand_detached(&def, &call, FragmentKind::Items).unwrap();
+    assert_eq!(node.kind(), MACRO_ITEMS);
+    assert_eq!(text, "fn bar () {}");
+}
+
 #[test]
 fn test_convert_tt() {
     parse_macro(r#"
@@ -1388,6 +1474,32 @@ impl MacroFixture {
         self.rules.expand(&invocation_tt)
     }
 
+    fn matching_rule(&self, invocation: &str) -> Option<usize> {
+        let source_file = ast::SourceFile::parse(invocation).tree();
+        let macro_invocation =
+            source_file.syntax().descendants().find_map(ast::MacroCall::cast).unwrap();
+
+        let (invocation_tt, _) =
+            ast_to_token_tree(&macro_invocation.token_tree().unwrap()).unwrap();
+
+        self.rules.matching_rule(&invocation_tt)
+    }
+
+    fn simple_bindings(&self, invocation: &str) -> Vec<(String, String)> {
+        let source_file = ast::SourceFile::parse(invocation).tree();
+        let macro_invocation =
+            source_file.syntax().descendants().find_map(ast::MacroCall::cast).unwrap();
+
+        let (invocation_tt, _) =
+            ast_to_token_tree(&macro_invocation.token_tree().unwrap()).unwrap();
+
+        self.rules
+            .simple_bindings(&invocation_tt)
+            .into_iter()
+            .map(|(name, tt)| (name.to_string(), tt.to_string()))
+            .collect()
+    }
+
     fn assert_expand_err(&self, invocation: &str, err: &ExpandError) {
         assert_eq!(self.try_expand_tt(invocation).as_ref(), Err(err));
     }
@@ -15,7 +15,7 @@ pub(super) enum StmtWithSemi {
 const EXPR_FIRST: TokenSet = LHS_FIRST;
 
 pub(super) fn expr(p: &mut Parser) -> (Option<CompletedMarker>, BlockLike) {
-    let r = Restrictions { forbid_structs: false, prefer_stmt: false };
+    let r = Restrictions { forbid_structs: false, prefer_stmt: false, allow_let: false };
     expr_bp(p, r, 1)
 }
 
@@ -40,12 +40,19 @@ pub(super) fn expr_with_attrs(p: &mut Parser) -> bool {
 }
 
 pub(super) fn expr_stmt(p: &mut Parser) -> (Option<CompletedMarker>, BlockLike) {
-    let r = Restrictions { forbid_structs: false, prefer_stmt: true };
+    let r = Restrictions { forbid_structs: false, prefer_stmt: true, allow_let: false };
     expr_bp(p, r, 1)
 }
 
 fn expr_no_struct(p: &mut Parser) {
-    let r = Restrictions { forbid_structs: true, prefer_stmt: false };
+    let r = Restrictions { forbid_structs: true, prefer_stmt: false, allow_let: false };
+    expr_bp(p, r, 1);
+}
+
+/// Parses the condition of an `if` or a `while`, which can chain `let`s with
+/// `&&`.
+fn expr_cond(p: &mut Parser) {
+    let r = Restrictions { forbid_structs: true, prefer_stmt: false, allow_let: true };
     expr_bp(p, r, 1);
 }
 
@@ -229,6 +236,7 @@ pub(crate) fn expr_block_contents(p: &mut Parser) {
 struct Restrictions {
     forbid_structs: bool,
     prefer_stmt: bool,
+    allow_let: bool,
 }
 
 /// Binding powers of operators for a Pratt parser.
@@ -292,7 +300,17 @@ fn expr_bp(p: &mut Parser, r: Restrictions, bp: u8) -> (Option<CompletedMarker>,
         }
         None => return (None, BlockLike::NotBlock),
     };
+    (Some(bin_expr_rest(p, r, bp, lhs)), BlockLike::NotBlock)
+}
 
+// Parses the operators with binding power of at least bp following an already
+// parsed lhs.
+fn bin_expr_rest(
+    p: &mut Parser,
+    r: Restrictions,
+    bp: u8,
+    mut lhs: CompletedMarker,
+) -> CompletedMarker {
     loop {
         let is_range = p.at(T![..]) || p.at(T![..=]);
         let (op_bp, op) = current_op(p);
@@ -329,7 +347,7 @@ fn expr_bp(p: &mut Parser, r: Restrictions, bp: u8) -> (Option<CompletedMarker>,
         expr_bp(p, r, op_bp + 1);
         lhs = m.complete(p, if is_range { RANGE_EXPR } else { BIN_EXPR });
     }
-    (Some(lhs), BlockLike::NotBlock)
+    lhs
 }
 
 const LHS_FIRST: TokenSet = atom::ATOM_EXPR_FIRST.union(token_set![AMP, STAR, EXCL, DOT, MINUS]);
@@ -345,7 +363,18 @@ fn lhs(p: &mut Parser, r: Restrictions) -> Option<(CompletedMarker, BlockLike)>
         T![&] => {
             m = p.start();
             p.bump(T![&]);
-            p.eat(T![mut]);
+            // test raw_ref_expr
+            // fn foo() {
+            //     let _ = &raw mut x;
+            //     let _ = &raw const x;
+            //     let _ = &raw;
+            // }
+            if p.at_contextual_kw("raw") && (p.nth_at(1, T![mut]) || p.nth_at(1, T![const])) {
+                p.bump_remap(T![raw]);
+                p.bump_any();
+            } else {
+                p.eat(T![mut]);
+            }
             REF_EXPR
         }
         // test unary_expr
@@ -51,6 +51,7 @@ pub(super) const ATOM_EXPR_FIRST: TokenSet =
         T![while],
         T![match],
         T![unsafe],
+        T![const],
         T![return],
         T![break],
         T![continue],
@@ -116,6 +117,16 @@ pub(super) fn atom_expr(p: &mut Parser, r: Restrictions) -> Option<(CompletedMar
             p.bump(T![unsafe]);
             block_expr(p, Some(m))
         }
+        // test const_block_expr
+        // fn foo() {
+        //     const { 92 };
+        //     let _: [u8; const { 1 + 1 }];
+        // }
+        T![const] if la == T!['{'] => {
+            let m = p.start();
+            p.bump(T![const]);
+            block_expr(p, Some(m))
+        }
         T!['{'] => {
             // test for_range_from
             // fn foo() {
@@ -128,6 +139,7 @@ pub(super) fn atom_expr(p: &mut Parser, r: Restrictions) -> Option<(CompletedMar
         T![return] => return_expr(p),
         T![continue] => continue_expr(p),
         T![break] => break_expr(p, r),
+        T![let] if r.allow_let => let_chain_cond(p),
         _ => {
             p.err_recover("expected expression", EXPR_RECOVERY_SET);
             return None;
@@ -333,12 +345,39 @@ fn for_expr(p: &mut Parser, m: Option<Marker>) -> CompletedMarker {
 // }
 fn cond(p: &mut Parser) {
     let m = p.start();
-    if p.eat(T![let]) {
-        patterns::pattern_top(p);
-        p.expect(T![=]);
+    if !p.at(T![let]) {
+        expr_cond(p);
+        m.complete(p, CONDITION);
+        return;
     }
-    expr_no_struct(p);
-    m.complete(p, CONDITION);
+    let_cond_body(p);
+    let lhs = m.complete(p, CONDITION);
+    if current_op(p).0 > 0 {
+        let r = Restrictions { forbid_structs: true, prefer_stmt: false, allow_let: true };
+        bin_expr_rest(p, r, 1, lhs).precede(p).complete(p, CONDITION);
+    }
+}
+
+// test let_chain
+// fn foo() {
+//     if let Some(a) = x && let Some(b) = y {}
+//     while a > 0 && let Some(b) = y {}
+//     if let Some(a) = x && a > 0 || let Some(b) = y && b == z {}
+// }
+fn let_chain_cond(p: &mut Parser) -> CompletedMarker {
+    assert!(p.at(T![let]));
+    let m = p.start();
+    let_cond_body(p);
+    m.complete(p, CONDITION)
+}
+
+/// Parses `let <pat> = <expr>`, where the expression binds tighter than `&&`
+/// so that the `let`s of a chain end up side by side.
+fn let_cond_body(p: &mut Parser) {
+    p.bump(T![let]);
+    patterns::pattern_top(p);
+    p.expect(T![=]);
+    expr_bp(p, Restrictions { forbid_structs: true, prefer_stmt: false, allow_let: false }, 5);
 }
 
 // test match_expr
@@ -352,6 +391,8 @@ fn match_expr(p: &mut Parser) -> CompletedMarker {
     assert!(p.at(T![match]));
     let m = p.start();
     p.bump(T![match]);
+    // test_err match_let
+    // fn foo() { match let Some(a) = x {} }
     expr_no_struct(p);
     if p.at(T!['{']) {
         match_arm_list(p);
@@ -556,7 +597,7 @@ fn meta_var_expr(p: &mut Parser) -> CompletedMarker {
     let m = p.start();
     p.bump(L_DOLLAR);
     let (completed, _is_block) =
-        expr_bp(p, Restrictions { forbid_structs: false, prefer_stmt: false }, 1);
+        expr_bp(p, Restrictions { forbid_structs: false, prefer_stmt: false, allow_let: false }, 1);
 
     match (completed, p.current()) {
         (Some(it), R_DOLLAR) => {
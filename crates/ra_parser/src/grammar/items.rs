@@ -89,7 +89,10 @@ pub(super) fn maybe_item(p: &mut Parser, m: Marker, flavor: ItemFlavor) -> Resul
     let mut has_mods = false;
 
     // modifiers
-    has_mods |= p.eat(T![const]);
+    if p.at(T![const]) && p.nth(1) != T!['{'] {
+        p.eat(T![const]);
+        has_mods = true;
+    }
 
     // test_err async_without_semicolon
     // fn foo() { let _ = async {} }
@@ -355,6 +358,10 @@ fn type_def(p: &mut Parser, m: Marker) {
     if p.eat(T![=]) {
         types::type_(p);
     }
+
+    // test type_item_where_clause_after_type
+    // type Foo<T> = Vec<T> where T: Copy;
+    type_params::opt_where_clause(p);
     p.expect(T![;]);
     m.complete(p, TYPE_ALIAS_DEF);
 }
@@ -70,6 +70,17 @@ fn type_const_param(p: &mut Parser, m: Marker) {
     p.bump(T![const]);
     name(p);
     types::ascription(p);
+    // test const_param_default
+    // struct S<const N: u32 = 4>;
+    // struct T<const N: usize = { 2 + 2 }>;
+    if p.at(T![=]) {
+        p.bump(T![=]);
+        if p.at(T!['{']) {
+            expressions::block(p);
+        } else if expressions::literal(p).is_none() {
+            p.error("expected a literal or a block");
+        }
+    }
     m.complete(p, CONST_PARAM);
 }
 
@@ -0,0 +1 @@
+fn foo() { match let Some(a) = x {} }
@@ -0,0 +1,4 @@
+fn foo() {
+    const { 92 };
+    let _: [u8; const { 1 + 1 }];
+}
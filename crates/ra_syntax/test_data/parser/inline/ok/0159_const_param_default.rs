@@ -0,0 +1,2 @@
+struct S<const N: u32 = 4>;
+struct T<const N: usize = { 2 + 2 }>;
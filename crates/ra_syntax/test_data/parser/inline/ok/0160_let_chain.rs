@@ -0,0 +1,5 @@
+fn foo() {
+    if let Some(a) = x && let Some(b) = y {}
+    while a > 0 && let Some(b) = y {}
+    if let Some(a) = x && a > 0 || let Some(b) = y && b == z {}
+}
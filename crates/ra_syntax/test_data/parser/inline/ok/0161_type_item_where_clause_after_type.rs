@@ -0,0 +1 @@
+type Foo<T> = Vec<T> where T: Copy;
@@ -0,0 +1,5 @@
+fn foo() {
+    let _ = &raw mut x;
+    let _ = &raw const x;
+    let _ = &raw;
+}
@@ -45,6 +45,9 @@ pub struct ServerConfig {
     pub feature_flags: FxHashMap<String, bool>,
 
     pub rustfmt_args: Vec<String>,
+    /// Whether to run `rustfmt` on macro expansions that consist of items.
+    #[serde(deserialize_with = "nullable_bool_false")]
+    pub expand_macro_rustfmt: bool,
 
     /// Cargo feature configurations.
     pub cargo_features: CargoFeatures,
@@ -66,6 +69,7 @@ impl Default for ServerConfig {
             feature_flags: FxHashMap::default(),
             cargo_features: Default::default(),
             rustfmt_args: Vec::new(),
+            expand_macro_rustfmt: false,
         }
     }
 }
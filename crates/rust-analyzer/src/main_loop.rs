@@ -179,6 +179,7 @@ pub fn main_loop(
                     all_targets: config.cargo_watch_all_targets,
                 },
                 rustfmt_args: config.rustfmt_args,
+                expand_macro_rustfmt: config.expand_macro_rustfmt,
             }
         };
 
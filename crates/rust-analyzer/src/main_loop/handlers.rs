@@ -7,6 +7,7 @@ use std::{
     fmt::Write as _,
     io::Write as _,
     process::{self, Stdio},
+    sync::{Arc, Mutex},
 };
 
 use lsp_server::ErrorCode;
@@ -21,8 +22,8 @@ use lsp_types::{
     SemanticTokensResult, SymbolInformation, TextDocumentIdentifier, TextEdit, WorkspaceEdit,
 };
 use ra_ide::{
-    AssistId, FileId, FilePosition, FileRange, Query, RangeInfo, Runnable, RunnableKind,
-    SearchScope,
+    AssistId, ExpandMacroConfig, FileId, FilePosition, FileRange, LineIndex, Query, RangeInfo,
+    Runnable, RunnableKind, SearchScope,
 };
 use ra_prof::profile;
 use ra_syntax::{AstNode, SyntaxKind, TextRange, TextUnit};
@@ -77,8 +78,46 @@ pub fn handle_expand_macro(
     match offset {
         None => Ok(None),
         Some(offset) => {
-            let res = world.analysis().expand_macro(FilePosition { file_id, offset })?;
-            Ok(res.map(|it| req::ExpandedMacro { name: it.name, expansion: it.expansion }))
+            let mut config = ExpandMacroConfig::default();
+            if world.options.expand_macro_rustfmt {
+                let rustfmt = Mutex::new(rustfmt_command(&world, &params.text_document)?);
+                config.formatter = Some(Arc::new(move |text: &str| {
+                    let mut rustfmt = rustfmt
+                        .lock()
+                        .ok()?
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .ok()?;
+                    rustfmt.stdin.as_mut()?.write_all(text.as_bytes()).ok()?;
+                    let output = rustfmt.wait_with_output().ok()?;
+                    if !output.status.success() {
+                        return None;
+                    }
+                    String::from_utf8(output.stdout).ok()
+                }));
+            }
+            let res =
+                world.analysis().expand_macro(FilePosition { file_id, offset }, &config)??;
+            let res = match res {
+                Some(it) => it,
+                None => return Ok(None),
+            };
+            let output_index = LineIndex::new(&res.expansion);
+            let source_map = res
+                .source_map
+                .into_iter()
+                .map(|(output_range, source)| {
+                    let source_index = world.analysis().file_line_index(source.file_id)?;
+                    let source = to_location(source.file_id, source.range, &world, &source_index)?;
+                    Ok(req::ExpansionSourceRange {
+                        output_range: output_range.conv_with(&output_index),
+                        source_uri: source.uri,
+                        source_range: source.range,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Some(req::ExpandedMacro { name: res.name, expansion: res.expansion, source_map }))
         }
     }
 }
@@ -580,18 +619,15 @@ pub fn handle_references(
     Ok(Some(locations))
 }
 
-pub fn handle_formatting(
-    world: WorldSnapshot,
-    params: DocumentFormattingParams,
-) -> Result<Option<Vec<TextEdit>>> {
-    let _p = profile("handle_formatting");
-    let file_id = params.text_document.try_conv_with(&world)?;
-    let file = world.analysis().file_text(file_id)?;
+/// Sets up `rustfmt` to format the document, with the user's arguments and
+/// from its directory, so that the project's `rustfmt.toml` is found.
+fn rustfmt_command(
+    world: &WorldSnapshot,
+    text_document: &TextDocumentIdentifier,
+) -> Result<process::Command> {
+    let file_id = text_document.try_conv_with(world)?;
     let crate_ids = world.analysis().crate_for(file_id)?;
 
-    let file_line_index = world.analysis().file_line_index(file_id)?;
-    let end_position = TextUnit::of_str(&file).conv_with(&file_line_index);
-
     let mut rustfmt = process::Command::new("rustfmt");
     rustfmt.args(&world.options.rustfmt_args);
     if let Some(&crate_id) = crate_ids.first() {
@@ -600,11 +636,26 @@ pub fn handle_formatting(
         rustfmt.args(&["--edition", &edition.to_string()]);
     }
 
-    if let Ok(path) = params.text_document.uri.to_file_path() {
+    if let Ok(path) = text_document.uri.to_file_path() {
         if let Some(parent) = path.parent() {
             rustfmt.current_dir(parent);
         }
     }
+    Ok(rustfmt)
+}
+
+pub fn handle_formatting(
+    world: WorldSnapshot,
+    params: DocumentFormattingParams,
+) -> Result<Option<Vec<TextEdit>>> {
+    let _p = profile("handle_formatting");
+    let file_id = params.text_document.try_conv_with(&world)?;
+    let file = world.analysis().file_text(file_id)?;
+
+    let file_line_index = world.analysis().file_line_index(file_id)?;
+    let end_position = TextUnit::of_str(&file).conv_with(&file_line_index);
+
+    let mut rustfmt = rustfmt_command(&world, &params.text_document)?;
     let mut rustfmt = rustfmt.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
 
     rustfmt.stdin.as_mut().unwrap().write_all(file.as_bytes())?;
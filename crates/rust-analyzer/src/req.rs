@@ -54,6 +54,16 @@ pub struct SyntaxTreeParams {
 pub struct ExpandedMacro {
     pub name: String,
     pub expansion: String,
+    pub source_map: Vec<ExpansionSourceRange>,
+}
+
+/// A range of the expansion, and where in the source its text was written.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpansionSourceRange {
+    pub output_range: Range,
+    pub source_uri: Url,
+    pub source_range: Range,
 }
 
 pub enum ExpandMacro {}
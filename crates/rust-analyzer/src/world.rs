@@ -35,6 +35,7 @@ pub struct Options {
     pub line_folding_only: bool,
     pub max_inlay_hint_length: Option<usize>,
     pub rustfmt_args: Vec<String>,
+    pub expand_macro_rustfmt: bool,
     pub cargo_watch: CheckOptions,
 }
 
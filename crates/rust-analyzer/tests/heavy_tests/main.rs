@@ -9,7 +9,8 @@ use lsp_types::{
 };
 use rust_analyzer::req::{
     CodeActionParams, CodeActionRequest, Completion, CompletionParams, DidOpenTextDocument,
-    Formatting, OnEnter, Runnables, RunnablesParams,
+    ExpandMacro, ExpandMacroParams, ExpansionSourceRange, Formatting, OnEnter, Runnables,
+    RunnablesParams,
 };
 use serde_json::json;
 use tempfile::TempDir;
@@ -580,3 +581,47 @@ version = \"0.0.0\"
         }),
     );
 }
+
+#[test]
+fn expand_macro_maps_expansion_to_source() {
+    if skip_slow_tests() {
+        return;
+    }
+
+    let server = project(
+        r#"
+//- Cargo.toml
+[package]
+name = "foo"
+version = "0.0.0"
+
+//- src/lib.rs
+macro_rules! inc { ($e:expr) => { $e + 1 } }
+fn f() -> i32 { inc!(92) }
+"#,
+    );
+    server.wait_until_workspace_is_loaded();
+    let res = server.send_request::<ExpandMacro>(ExpandMacroParams {
+        text_document: server.doc_id("src/lib.rs"),
+        position: Some(Position::new(1, 17)),
+    });
+    assert_eq!(res["expansion"], "92 + 1");
+
+    let source_map: Vec<ExpansionSourceRange> =
+        serde_json::from_value(res["sourceMap"].clone()).unwrap();
+    let ranges: Vec<_> = source_map.iter().map(|it| (it.output_range, it.source_range)).collect();
+    let range = |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+    assert_eq!(
+        ranges,
+        vec![
+            (range(0, 0, 2), range(1, 21, 23)),
+            (range(0, 3, 4), range(0, 37, 38)),
+            (range(0, 5, 6), range(0, 39, 40)),
+        ]
+    );
+    assert!(source_map.iter().all(|it| it.source_uri.as_str().ends_with("src/lib.rs")));
+
+    let round_tripped: Vec<ExpansionSourceRange> =
+        serde_json::from_value(serde_json::to_value(&source_map).unwrap()).unwrap();
+    assert_eq!(round_tripped, source_map);
+}
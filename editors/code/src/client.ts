@@ -40,6 +40,7 @@ export async function createClient(config: Config, serverPath: string): Promise<
             withSysroot: config.withSysroot,
             cargoFeatures: config.cargoFeatures,
             rustfmtArgs: config.rustfmtArgs,
+            expandMacroRustfmt: config.expandMacroRustfmt,
         },
         traceOutputChannel,
     };
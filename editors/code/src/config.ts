@@ -154,6 +154,7 @@ export class Config {
     get useClientWatching() { return this.cfg.get("useClientWatching") as boolean; }
     get featureFlags() { return this.cfg.get("featureFlags") as Record<string, boolean>; }
     get rustfmtArgs() { return this.cfg.get("rustfmtArgs") as string[]; }
+    get expandMacroRustfmt() { return this.cfg.get("expandMacroRustfmt") as boolean; }
 
     get cargoWatchOptions(): CargoWatchOptions {
         return {
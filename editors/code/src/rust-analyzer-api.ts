@@ -36,6 +36,12 @@ export interface ExpandMacroParams {
 export interface ExpandedMacro {
     name: string;
     expansion: string;
+    sourceMap: Vec<ExpansionSourceRange>;
+}
+export interface ExpansionSourceRange {
+    outputRange: lc.Range;
+    sourceUri: string;
+    sourceRange: lc.Range;
 }
 export const expandMacro = request<ExpandMacroParams, Option<ExpandedMacro>>("expandMacro");
 